@@ -0,0 +1,16 @@
+//! Host build of the firmware modules that hold pure logic, so their unit tests run with
+//! `cargo test` from this directory. The firmware itself only builds for thumbv7em.
+
+#[path = "../../src/debounce.rs"]
+pub mod debounce;
+#[path = "../../src/gestures.rs"]
+pub mod gestures;
+#[path = "../../src/keymap.rs"]
+pub mod keymap;
+#[path = "../../src/panel.rs"]
+pub mod panel;
+#[path = "../../src/relays.rs"]
+pub mod relays;
+
+// The firmware re-exports these from its crate root; keymap and panel refer to them there.
+pub use gestures::{Button, ButtonsEvent, CHORD_PC_FREQ, CHORD_PC_POL};
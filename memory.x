@@ -1,6 +1,7 @@
 /* STM32L432KCU3: 256KB Flash, 64KB SRAM */
+/* Top 8KB of flash (0x0803E000) is reserved for storage.rs */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 256K
+  FLASH : ORIGIN = 0x08000000, LENGTH = 248K
   RAM   : ORIGIN = 0x20000000, LENGTH = 64K
 }
@@ -1,50 +1,87 @@
 use defmt::*;
 use embassy_stm32::exti::ExtiInput;
-use embassy_time::Timer;
+use embassy_time::{Instant, Timer};
+use embassy_futures::join::join4;
+use embassy_futures::select::{select, Either};
 use embassy_sync::channel::mpmc::Channel;
-use crate::ButtonsEvent;
+use crate::deadman;
+use crate::debounce::Debouncer;
+use crate::gestures::{self, Events, GestureEngine};
+use crate::{Button, ButtonsEvent};
 
 const DEBOUNCE_MS: u64 = 30;
-const LONG_PRESS_MS_PC: u64 = 800;
-const LONG_PRESS_MS_FREQ: u64 = 1000;
+const IDLE_POLL_MS: u64 = 50; // re-sample even without an edge, in case one was missed
+const DEADMAN_POLL_MS: u64 = 5; // while a dead-man hold is active, a missed release edge costs at most this
 
-#[embassy_executor::task]
-pub async fn buttons_task<'d>(
-    mut pb0_pc: ExtiInput<'d>,
-    mut pa9_pol: ExtiInput<'d>,
-    mut pa12_freq: ExtiInput<'d>,
-    tx: Channel<ButtonsEvent, 8>::Sender,
-) {
+type Edge = (Button, bool, u64);
+
+/// One button, independently of the others: sample on every edge and at the debouncer's deadlines.
+/// As the dead-man button its presses enable HV instead of feeding gestures, and any raw
+/// release ends the hold at once, before debouncing.
+async fn watch<'d>(btn: &mut ExtiInput<'d>, button: Button, edges: &Channel<Edge, 8>::Sender, tx: &Channel<ButtonsEvent, 8>::Sender) {
+    let mut deb = Debouncer::new(DEBOUNCE_MS);
+    let mut hold: Option<u64> = None;
+    let mut forwarded = false; // the gesture engine saw this press, so it must see the release
     loop {
-        pb0_pc.wait_for_falling_edge().await;
-        Timer::after_millis(DEBOUNCE_MS).await;
-        if pb0_pc.is_low() {
-            let t0 = embassy_time::Instant::now();
-            while pb0_pc.is_low() {
-                if t0.elapsed().as_millis() as u64 >= LONG_PRESS_MS_PC { let _ = tx.send(ButtonsEvent::PcLong).await; while pb0_pc.is_low() { Timer::after_millis(10).await; } continue; }
-                Timer::after_millis(10).await;
+        let now = Instant::now().as_millis();
+        let pressed = btn.is_low();
+        let dm = deadman::mode().filter(|d| d.button == button);
+        if let Some(since) = hold {
+            match dm {
+                _ if !pressed => { hold = None; deadman::release("released"); }
+                Some(d) if now - since >= d.max_hold_ms as u64 => { hold = None; deadman::release("max hold time"); }
+                None => { hold = None; deadman::release("mode changed"); }
+                Some(_) => deadman::refresh(now),
             }
-            let _ = tx.send(ButtonsEvent::PcShort).await;
         }
-        pa9_pol.wait_for_falling_edge().await;
-        Timer::after_millis(DEBOUNCE_MS).await;
-        if pa9_pol.is_low() {
-            let t0 = embassy_time::Instant::now();
-            while pa9_pol.is_low() {
-                if t0.elapsed().as_millis() as u64 >= 800 { let _ = tx.send(ButtonsEvent::PolarityLong).await; while pa9_pol.is_low() { Timer::after_millis(10).await; } continue; }
-                Timer::after_millis(10).await;
+        match deb.update(pressed, now) {
+            Some((true, at)) if dm.is_some() => {
+                hold = Some(at); deadman::press(now);
+                // Never park here with the hold in force: a release must keep being sampled
+                if tx.try_send(ButtonsEvent::DeadManHeld).is_err() { hold = None; deadman::release("event queue full"); }
             }
-            let _ = tx.send(ButtonsEvent::PolarityShort).await;
+            Some((true, at)) => { forwarded = true; let _ = edges.send((button, true, at)).await; }
+            Some((false, at)) if forwarded => { forwarded = false; let _ = edges.send((button, false, at)).await; }
+            _ => {}
         }
-        pa12_freq.wait_for_falling_edge().await;
-        Timer::after_millis(DEBOUNCE_MS).await;
-        if pa12_freq.is_low() {
-            let t0 = embassy_time::Instant::now();
-            while pa12_freq.is_low() {
-                if t0.elapsed().as_millis() as u64 >= LONG_PRESS_MS_FREQ { let _ = tx.send(ButtonsEvent::FreqLong).await; while pa12_freq.is_low() { Timer::after_millis(10).await; } continue; }
-                Timer::after_millis(10).await;
+        let mut wake = deb.deadline().unwrap_or(now + IDLE_POLL_MS);
+        if let (Some(since), Some(d)) = (hold, dm) { wake = wake.min(now + DEADMAN_POLL_MS).min(since + d.max_hold_ms as u64); }
+        select(btn.wait_for_any_edge(), Timer::at(Instant::from_millis(wake))).await;
+    }
+}
+
+/// Turn debounced edges from all buttons into gestures.
+async fn recognise(edges: &Channel<Edge, 8>::Receiver, tx: &Channel<ButtonsEvent, 8>::Sender) {
+    let mut engine = GestureEngine::new(gestures::config());
+    loop {
+        let wake = engine.deadline().map_or(Instant::MAX, Instant::from_millis);
+        let mut out = Events::new();
+        match select(edges.receive(), Timer::at(wake)).await {
+            Either::First((button, down, at)) => {
+                if down { engine.set_config(gestures::config()); }
+                engine.poll(at, &mut out);
+                engine.input(button, down, at, &mut out);
             }
-            let _ = tx.send(ButtonsEvent::FreqShort).await;
+            Either::Second(()) => engine.poll(Instant::now().as_millis(), &mut out),
         }
+        for e in out { debug!("Button gesture {}", e); let _ = tx.send(e).await; }
     }
 }
+
+#[embassy_executor::task]
+pub async fn buttons_task<'d>(
+    mut pb0_pc: ExtiInput<'d>,
+    mut pa9_pol: ExtiInput<'d>,
+    mut pa12_freq: ExtiInput<'d>,
+    tx: Channel<ButtonsEvent, 8>::Sender,
+) {
+    info!("Buttons: PB0/PA9/PA12 monitored concurrently");
+    let edges: Channel<Edge, 8> = Channel::new();
+    let sender = edges.sender();
+    join4(
+        watch(&mut pb0_pc, Button::Pc, &sender, &tx),
+        watch(&mut pa9_pol, Button::Polarity, &sender, &tx),
+        watch(&mut pa12_freq, Button::Freq, &sender, &tx),
+        recognise(&edges.receiver(), &tx),
+    ).await;
+}
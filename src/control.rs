@@ -0,0 +1,348 @@
+use core::fmt::Write;
+use embassy_sync::channel::mpmc::Channel;
+use heapless::{String, Vec};
+use crate::hv_control::{self, AltTrigger, CinRange, HvCommand, HvState, Polarity};
+use crate::dac_control::{self, DacCmd};
+use crate::drivers::pulse_timer;
+use crate::drivers::soft_uart::SoftUart;
+use crate::frequency_control::{self, ExtTrigger, FrequencyCmd, PresetCmd, Sweep, SweepLength, SweepScale, TrainMode, TriggerMode, TriggerSource};
+use crate::envelope::{self, Action, Band, EnvelopeError, Violation};
+use crate::gestures::{self, Hold};
+use crate::keymap::{self, Action as KeyAction, Gesture};
+use crate::panel::{self, Confirm, Dangerous, Lock};
+use crate::deadman::{self, DeadMan};
+use crate::Button;
+use crate::relays::RELAYS;
+use crate::safety::{self, StopReason};
+
+/* Service/control interface on the software UART (TX PA8, RX PA11, 9600 8N1): ASCII lines,
+   one command per line. Every command gets at least one reply line: "ok ...", "err ..." or
+   the requested report. */
+
+const LINE_MAX: usize = 64;
+/// Longer replies are cut short rather than split.
+const REPLY_MAX: usize = 192;
+
+type Uart = SoftUart<'static>;
+
+/// Format one reply line and send it, terminated by CRLF.
+macro_rules! reply {
+    ($uart:expr, $($arg:tt)*) => {{
+        let mut s: String<REPLY_MAX> = String::new();
+        let _ = write!(s, $($arg)*);
+        $uart.write(s.as_bytes()).await;
+        $uart.write(b"\r\n").await;
+    }};
+}
+
+#[derive(Copy, Clone, Debug, defmt::Format)]
+enum Request { Status, Relays, Stop(StopReason), Hv(HvCommand), Freq(FrequencyCmd), Env(EnvCmd), Btn(BtnCmd), Key(KeyCmd), Panel(PanelCmd), DeadMan(Option<DeadMan>) }
+
+/// Operating envelope edits, applied directly; they bind the next voltage/frequency request.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+enum EnvCmd { Show, Band(u8, Band), Remove(u8), Action(Action), CinDependent(bool), Derate(CinRange, u16), CinMax(CinRange, u32) }
+
+/// Button gesture timings, applied from the next press.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+enum BtnCmd { Show, Long(Button, u32), Double(Button, u32), Hold(Button, Hold), Repeat { delay_ms: u32, every_ms: u32, min_ms: u32, accel: u32 }, Chord { window_ms: u32, hold_ms: u32 } }
+
+/// Key map edits; `Bind(g, None)` unbinds the gesture.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+enum KeyCmd { Show, Bind(Gesture, Option<KeyAction>), Defaults }
+
+/// Panel lock and confirmation settings.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+enum PanelCmd { Show, Lock(bool), Confirm(Dangerous, Confirm) }
+
+fn parse_button(b: &str) -> Option<Button> {
+    match b { "pc" => Some(Button::Pc), "pol" => Some(Button::Polarity), "freq" => Some(Button::Freq), _ => None }
+}
+
+/// "click:pc", "double:freq", "long:pol", "repeat:pc" or "chord:pc+pol".
+fn parse_gesture(s: &str) -> Option<Gesture> {
+    let (kind, b) = s.split_once(':')?;
+    Some(match kind {
+        "click" => Gesture::Click(parse_button(b)?),
+        "double" => Gesture::DoubleClick(parse_button(b)?),
+        "long" => Gesture::Long(parse_button(b)?),
+        "repeat" => Gesture::Repeat(parse_button(b)?),
+        "chord" => {
+            let mask = b.split('+').try_fold(0u8, |m, b| Some(m | parse_button(b)?.bit()))?;
+            if mask.count_ones() < 2 { return None; }
+            Gesture::Chord(mask)
+        }
+        _ => return None,
+    })
+}
+
+/// "12", "0.5" or "333.333" Hz -> mHz.
+fn parse_mhz(s: &str) -> Option<u32> {
+    let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+    if frac.len() > 3 { return None; }
+    let mut mhz = int.parse::<u32>().ok()?.checked_mul(1000)?;
+    if !frac.is_empty() { mhz = mhz.checked_add(frac.parse::<u32>().ok()? * 10u32.pow(3 - frac.len() as u32))?; }
+    Some(mhz)
+}
+
+fn parse(line: &str) -> Option<Request> {
+    let mut w = line.split_whitespace();
+    let cin = |c: &str| match c { "base" => Some(CinRange::Base), "1" => Some(CinRange::Bank1), "2" => Some(CinRange::Bank2), "both" => Some(CinRange::Both), _ => None };
+    let num = |s: Option<&str>| s?.parse::<u32>().ok();
+    let on_off = |s: &str| match s { "on" => Some(true), "off" => Some(false), _ => None };
+    let pol = |p: &str| match p { "+" => Some(Polarity::Positive), "-" => Some(Polarity::Negative), _ => None };
+    let req = match (w.next()?, w.next()) {
+        ("status", None) => Request::Status,
+        ("relays", None) => Request::Relays,
+        ("arm", None) => Request::Hv(HvCommand::Arm),
+        ("enable", None) => Request::Hv(HvCommand::Enable),
+        ("disable", None) => Request::Hv(HvCommand::Disable),
+        ("stop", None) => Request::Stop(StopReason::Operator),
+        ("estop", None) => Request::Stop(StopReason::EmergencyStop),
+        ("reset", None) => Request::Hv(HvCommand::ResetFaults),
+        ("pol", Some(p)) => Request::Hv(HvCommand::SetPolarity(pol(p)?)),
+        ("alt", Some("ms")) => Request::Hv(HvCommand::StartAlternating(AltTrigger::IntervalMs(w.next()?.parse().ok()?))),
+        ("alt", Some("pulses")) => Request::Hv(HvCommand::StartAlternating(AltTrigger::Pulses(w.next()?.parse().ok()?))),
+        ("alt", Some("stop")) => Request::Hv(HvCommand::StopAlternating(match w.next() { Some(p) => Some(pol(p)?), None => None })),
+        ("cin", Some(c)) => Request::Hv(HvCommand::SelectCin(cin(c)?)),
+        ("env", None) => Request::Env(EnvCmd::Show),
+        // env band <i> <max Hz> <max V>
+        ("env", Some("band")) => Request::Env(EnvCmd::Band(w.next()?.parse().ok()?, Band { max_freq_mhz: parse_mhz(w.next()?)?, max_v: w.next()?.parse().ok()? })),
+        ("env", Some("rm")) => Request::Env(EnvCmd::Remove(w.next()?.parse().ok()?)),
+        ("env", Some("clamp")) => Request::Env(EnvCmd::Action(Action::Clamp)),
+        ("env", Some("reject")) => Request::Env(EnvCmd::Action(Action::Reject)),
+        ("env", Some("cin")) => Request::Env(EnvCmd::CinDependent(on_off(w.next()?)?)),
+        ("env", Some("derate")) => Request::Env(EnvCmd::Derate(cin(w.next()?)?, w.next()?.parse().ok().filter(|p| *p <= 1000)?)),
+        // env cinmax <cin> <max Hz>
+        ("env", Some("cinmax")) => Request::Env(EnvCmd::CinMax(cin(w.next()?)?, parse_mhz(w.next()?).filter(|f| *f > 0)?)),
+        ("btn", None) => Request::Btn(BtnCmd::Show),
+        // btn repeat <delay ms> <every ms> <min ms> <accel every n>
+        ("btn", Some("repeat")) => Request::Btn(BtnCmd::Repeat { delay_ms: num(w.next())?, every_ms: num(w.next())?, min_ms: num(w.next())?, accel: num(w.next()).filter(|n| *n > 0)? }),
+        // btn chord <window ms> <hold ms>
+        ("btn", Some("chord")) => Request::Btn(BtnCmd::Chord { window_ms: num(w.next())?, hold_ms: num(w.next())? }),
+        // btn pc|pol|freq long <ms> | double <ms> (0 = off) | hold long|repeat
+        ("btn", Some(b)) => {
+            let b = parse_button(b)?;
+            Request::Btn(match w.next()? {
+                "long" => BtnCmd::Long(b, num(w.next())?),
+                "double" => BtnCmd::Double(b, num(w.next())?),
+                "hold" => BtnCmd::Hold(b, match w.next()? { "long" => Hold::Long, "repeat" => Hold::Repeat, _ => return None }),
+                _ => return None,
+            })
+        }
+        ("key", None) => Request::Key(KeyCmd::Show),
+        ("key", Some("defaults")) => Request::Key(KeyCmd::Defaults),
+        // key <gesture> up|down|ramp|stop|estop|pol|next|prev|preset <i>|capture|cin|reset|lock|none
+        ("key", Some(g)) => Request::Key(KeyCmd::Bind(parse_gesture(g)?, match w.next()? {
+            "up" => Some(KeyAction::StepUp),
+            "down" => Some(KeyAction::StepDown),
+            "ramp" => Some(KeyAction::StartRamp),
+            "stop" => Some(KeyAction::Stop),
+            "estop" => Some(KeyAction::EmergencyStop),
+            "pol" => Some(KeyAction::TogglePolarity),
+            "next" => Some(KeyAction::CycleNext),
+            "prev" => Some(KeyAction::CyclePrev),
+            "preset" => Some(KeyAction::RecallPreset(w.next()?.parse().ok()?)),
+            "capture" => Some(KeyAction::ToggleCapture),
+            "cin" => Some(KeyAction::CycleCin),
+            "reset" => Some(KeyAction::ResetFaults),
+            "lock" => Some(KeyAction::ToggleLock),
+            "none" => None,
+            _ => return None,
+        })),
+        ("deadman", Some("off")) => Request::DeadMan(None),
+        // deadman pc|pol|freq <max hold s>
+        ("deadman", Some(b)) => Request::DeadMan(Some(DeadMan { button: parse_button(b)?, max_hold_ms: num(w.next()).filter(|s| *s > 0)?.checked_mul(1000)? })),
+        ("panel", None) => Request::Panel(PanelCmd::Show),
+        ("panel", Some("lock")) => Request::Panel(PanelCmd::Lock(true)),
+        ("panel", Some("unlock")) => Request::Panel(PanelCmd::Lock(false)),
+        // confirm pol|ramp|range off|hold|twice <window ms>
+        ("confirm", Some(d)) => {
+            let d = match d { "pol" => Dangerous::Polarity, "ramp" => Dangerous::Ramp, "range" => Dangerous::Range, _ => return None };
+            Request::Panel(PanelCmd::Confirm(d, match w.next()? {
+                "off" => Confirm::None,
+                "hold" => Confirm::Hold,
+                "twice" => Confirm::Twice { window_ms: num(w.next())? },
+                _ => return None,
+            }))
+        }
+        ("freq", Some(f)) => Request::Freq(FrequencyCmd::SetFrequency(parse_mhz(f)?)),
+        ("width", Some(us)) => Request::Freq(FrequencyCmd::SetPulseWidthUs(us.parse().ok()?)),
+        ("capture", Some("on")) => Request::Freq(FrequencyCmd::EnterInputCaptureMode),
+        ("capture", Some("off")) => Request::Freq(FrequencyCmd::ExitInputCaptureMode),
+        ("capture", Some("slave")) => Request::Freq(FrequencyCmd::SetCaptureSlave(true)),
+        ("capture", Some("free")) => Request::Freq(FrequencyCmd::SetCaptureSlave(false)),
+        ("next", None) => Request::Freq(FrequencyCmd::CycleNext),
+        ("prev", None) => Request::Freq(FrequencyCmd::CyclePrev),
+        ("preset", Some(op)) => Request::Freq(FrequencyCmd::Preset(match op {
+            "list" => PresetCmd::List,
+            "add" => PresetCmd::Add(parse_mhz(w.next()?)?),
+            "set" => PresetCmd::Set(w.next()?.parse().ok()?, parse_mhz(w.next()?)?),
+            "rm" => PresetCmd::Remove(w.next()?.parse().ok()?),
+            "clear" => PresetCmd::Clear,
+            "recall" => PresetCmd::Recall(w.next()?.parse().ok()?),
+            "off" => PresetCmd::IncludeOff(on_off(w.next()?)?),
+            "wrap" => PresetCmd::Wrap(on_off(w.next()?)?),
+            "save" => PresetCmd::Save,
+            "defaults" => PresetCmd::Defaults,
+            _ => return None,
+        })),
+        ("train", Some("pulses")) => Request::Freq(FrequencyCmd::StartTrain(TrainMode::Pulses(num(w.next())?))),
+        ("train", Some("bursts")) => Request::Freq(FrequencyCmd::StartTrain(TrainMode::Bursts { pulses: num(w.next())?, gap_ms: num(w.next())?, bursts: num(w.next())? })),
+        ("train", Some("onoff")) => Request::Freq(FrequencyCmd::StartTrain(TrainMode::OnOff { on_s: num(w.next())?, off_s: num(w.next())?, cycles: num(w.next())? })),
+        ("train", Some("stop")) => Request::Freq(FrequencyCmd::StopTrain),
+        ("sweep", Some("stop")) => Request::Freq(FrequencyCmd::StopSweep),
+        // sweep lin|log <start Hz> <stop Hz> steps <n> [dwell ms] [repeat] | ... ms <duration> [repeat]
+        ("sweep", Some(sc)) => {
+            let scale = match sc { "lin" => SweepScale::Linear, "log" => SweepScale::Log, _ => return None };
+            let (start_mhz, stop_mhz) = (parse_mhz(w.next()?)?, parse_mhz(w.next()?)?);
+            let (mut length, mut rest) = match w.next()? {
+                "steps" => (SweepLength::Steps { count: w.next()?.parse().ok()?, dwell_ms: None }, w.next()),
+                "ms" => (SweepLength::DurationMs(num(w.next())?), w.next()),
+                _ => return None,
+            };
+            if let (SweepLength::Steps { dwell_ms, .. }, Some(d)) = (&mut length, num(rest)) { *dwell_ms = Some(d); rest = w.next(); }
+            let repeat = match rest { None => false, Some("repeat") => true, _ => return None };
+            Request::Freq(FrequencyCmd::StartSweep(Sweep { start_mhz, stop_mhz, scale, length, repeat }))
+        }
+        ("trigger", Some("off")) => Request::Freq(FrequencyCmd::SetTrigger(None)),
+        ("trigger", Some(m @ ("gate" | "pulses"))) => {
+            let mode = if m == "gate" { TriggerMode::Gated } else { TriggerMode::Pulses(num(w.next())?) };
+            let active_high = match w.next()? { "high" | "rise" => true, "low" | "fall" => false, _ => return None };
+            let delay_us = num(w.next())?;
+            let source = match w.next() { None => TriggerSource::Pa2, Some("soft") => TriggerSource::Software, _ => return None };
+            Request::Freq(FrequencyCmd::SetTrigger(Some(ExtTrigger { source, mode, active_high, delay_us })))
+        }
+        ("loopback", Some(l)) => Request::Freq(FrequencyCmd::SetLoopback(on_off(l)?)),
+        // marker <pulse|burst|polarity|hv> on|off
+        ("marker", Some(ev)) => {
+            let (mut m, on) = (pulse_timer::markers(), on_off(w.next()?)?);
+            match ev { "pulse" => m.pulse = on, "burst" => m.burst = on, "polarity" => m.polarity = on, "hv" => m.hv_enable = on, _ => return None }
+            Request::Freq(FrequencyCmd::SetMarkers(m))
+        }
+        ("fire", None) => Request::Freq(FrequencyCmd::SoftTrigger(true)),
+        ("gate", Some(g)) => Request::Freq(FrequencyCmd::SoftTrigger(on_off(g)?)),
+        ("duty", Some(d)) => Request::Freq(FrequencyCmd::SetDutyPermille(d.parse().ok()?)),
+        _ => return None,
+    };
+    if w.next().is_some() { return None; }
+    Some(req)
+}
+
+/// After an edit, bring a running stage back inside the envelope: lower the setpoint and
+/// frequency under `Action::Clamp`, stop it under `Action::Reject` or when a queue is full.
+/// Returns the violation found and whether it was clamped.
+fn enforce_envelope(dac_tx: &Channel<DacCmd, 8>::Sender, freq_tx: &Channel<FrequencyCmd, 8>::Sender) -> Option<(Violation, bool)> {
+    let hv = hv_control::status();
+    if hv.state != HvState::Running { return None; }
+    let (f, v) = (frequency_control::current_mhz(), dac_control::setpoint_v());
+    let (action, (max_f, max_v)) = envelope::with(|e| (e.action, e.live_limits(f, hv.cin)));
+    let violation = if f > max_f { Violation::FrequencyTooHigh { max_mhz: max_f, volts: v } }
+        else if v > max_v { Violation::VoltageTooHigh { max_v, freq_mhz: f } }
+        else { return None };
+    // Voltage first, so the lower frequency is not checked against the old setpoint
+    let clamped = action == Action::Clamp && max_v > 0.0
+        && (v <= max_v || dac_tx.try_send(DacCmd::SetHvVolts(max_v)).is_ok())
+        && (f <= max_f || freq_tx.try_send(FrequencyCmd::SetFrequency(max_f)).is_ok());
+    if !clamped { safety::stop(StopReason::OutsideEnvelope); }
+    Some((violation, clamped))
+}
+
+async fn envelope_cmd(uart: &mut Uart, cmd: EnvCmd, dac_tx: &Channel<DacCmd, 8>::Sender, freq_tx: &Channel<FrequencyCmd, 8>::Sender) {
+    let r = envelope::with_mut(|e| {
+        let mut bands: Vec<Band, { envelope::MAX_BANDS }> = Vec::from_slice(e.bands()).unwrap();
+        match cmd {
+            EnvCmd::Show => {}
+            EnvCmd::Band(i, b) => match bands.get_mut(i as usize) {
+                Some(x) => *x = b,
+                None if i as usize == bands.len() => bands.push(b).map_err(|_| EnvelopeError::TooManyBands)?,
+                None => return Err(EnvelopeError::BadIndex),
+            },
+            EnvCmd::Remove(i) if (i as usize) < bands.len() => { bands.remove(i as usize); }
+            EnvCmd::Remove(_) => return Err(EnvelopeError::BadIndex),
+            EnvCmd::Action(a) => e.action = a,
+            EnvCmd::CinDependent(on) => e.cin_dependent = on,
+            EnvCmd::Derate(c, p) => e.cin_permille[c as usize] = p,
+            EnvCmd::CinMax(c, f) => e.cin_max_mhz[c as usize] = f,
+        }
+        e.set_bands(&bands)
+    });
+    match r {
+        Ok(()) => {
+            let e = envelope::with(|e| e.clone());
+            reply!(uart, "envelope {:?}", e);
+            match enforce_envelope(dac_tx, freq_tx) {
+                Some((v, true)) => reply!(uart, "envelope: running stage {:?}, clamped", v),
+                Some((v, false)) => reply!(uart, "envelope: running stage {:?}, stopped", v),
+                None => {}
+            }
+        }
+        Err(err) => reply!(uart, "err envelope {:?}: {:?}", cmd, err),
+    }
+}
+
+async fn button_cmd(uart: &mut Uart, cmd: BtnCmd) {
+    let mut c = gestures::config();
+    match cmd {
+        BtnCmd::Show => {}
+        BtnCmd::Long(b, ms) => c.buttons[b as usize].long_ms = ms,
+        BtnCmd::Double(b, ms) => c.buttons[b as usize].double_ms = ms,
+        BtnCmd::Hold(b, h) => c.buttons[b as usize].hold = h,
+        BtnCmd::Repeat { delay_ms, every_ms, min_ms, accel } => {
+            c.repeat_delay_ms = delay_ms; c.repeat_ms = every_ms; c.repeat_min_ms = min_ms.min(every_ms); c.accel_every = accel;
+        }
+        BtnCmd::Chord { window_ms, hold_ms } => { c.chord_window_ms = window_ms; c.chord_hold_ms = hold_ms; }
+    }
+    gestures::set_config(c);
+    reply!(uart, "buttons {:?}", c);
+}
+
+#[embassy_executor::task]
+pub async fn control_task(
+    mut uart: Uart,
+    dac_tx: Channel<DacCmd, 8>::Sender,
+    hv_tx: Channel<HvCommand, 8>::Sender,
+    freq_tx: Channel<FrequencyCmd, 8>::Sender,
+) {
+    let mut line: Vec<u8, LINE_MAX> = Vec::new();
+    loop {
+        let b = uart.read_byte().await;
+        if b != b'\n' && b != b'\r' {
+            if line.push(b).is_err() { reply!(uart, "err line too long"); line.clear(); }
+            continue;
+        }
+        if line.is_empty() { continue; }
+        let req = core::str::from_utf8(&line).ok().and_then(parse);
+        line.clear();
+        match req {
+            Some(Request::Stop(r)) => { safety::stop(r); reply!(uart, "ok {:?}", r); }
+            Some(Request::Status) => {
+                reply!(uart, "status {:?} relays_eol={}", hv_control::status(), hv_control::relay_counters().near_end_of_life().count());
+                reply!(uart, "freq={}mHz achieved={}mHz measured={:?}", frequency_control::current_mhz(), frequency_control::achieved_mhz(), frequency_control::measured());
+                reply!(uart, "train={:?} sweep={:?} loopback={:?}", frequency_control::train_progress(), frequency_control::sweep_progress(), frequency_control::loopback());
+            }
+            Some(Request::Relays) => {
+                let c = hv_control::relay_counters();
+                for r in RELAYS {
+                    let n = c.ops[r as usize];
+                    reply!(uart, "relay {:?} ops={} warn_at={}{}", r, n, r.warn_threshold(), if n >= r.warn_threshold() { " eol" } else { "" });
+                }
+            }
+            // Never wait on a queue here: a stop line behind a busy task must still be read
+            Some(Request::Hv(cmd)) => match hv_tx.try_send(cmd) { Ok(()) => reply!(uart, "ok {:?}", cmd), Err(_) => reply!(uart, "err busy") },
+            Some(Request::Freq(cmd)) => match freq_tx.try_send(cmd) { Ok(()) => reply!(uart, "ok {:?}", cmd), Err(_) => reply!(uart, "err busy") },
+            Some(Request::Env(cmd)) => envelope_cmd(&mut uart, cmd, &dac_tx, &freq_tx).await,
+            Some(Request::Btn(cmd)) => button_cmd(&mut uart, cmd).await,
+            Some(Request::DeadMan(m)) => { deadman::set_mode(m); reply!(uart, "ok deadman {:?}", m); }
+            Some(Request::Panel(PanelCmd::Lock(on))) => { panel::set_lock(if on { Lock::Remote } else { Lock::Unlocked }); reply!(uart, "ok panel {:?}", panel::lock()); }
+            Some(Request::Panel(PanelCmd::Confirm(d, c))) => { panel::set_confirm(d, c); reply!(uart, "ok confirm {:?} {:?}", d, c); }
+            Some(Request::Panel(PanelCmd::Show)) => reply!(uart, "panel {:?} confirm {:?} deadman {:?} held={}", panel::lock(), panel::confirm_config(), deadman::mode(), deadman::held()),
+            Some(Request::Key(KeyCmd::Show)) => { for b in keymap::bindings() { reply!(uart, "key {:?} -> {:?}", b.gesture, b.action); } }
+            Some(Request::Key(KeyCmd::Defaults)) => { keymap::defaults(); reply!(uart, "ok key defaults"); }
+            Some(Request::Key(KeyCmd::Bind(g, a))) => match keymap::set(g, a) {
+                Ok(()) => reply!(uart, "ok key {:?} -> {:?}", g, a),
+                Err(e) => reply!(uart, "err key {:?}: {:?}", g, e),
+            },
+            None => reply!(uart, "err unknown command"),
+        }
+    }
+}
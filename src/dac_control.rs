@@ -1,20 +1,37 @@
+use core::sync::atomic::{AtomicU32, Ordering};
 use defmt::*;
 use embassy_time::Timer;
 use embassy_sync::channel::mpmc::Channel;
 use embassy_stm32::dac::{Dac, Channel as DacChannel};
+use crate::envelope::{self, Violation};
+use crate::{frequency_control, hv_control, safety};
 
+/// `ShortStep(n)` moves the setpoint by `n` steps of `HV_STEP_V`; negative steps lower it.
 #[derive(Copy, Clone, Debug, defmt::Format)]
-pub enum DacCmd { SetHvVolts(f32), ShortStep, StartRamp }
+pub enum DacCmd { SetHvVolts(f32), ShortStep(i8), StartRamp }
 
-pub struct DacController { hv_setpoint_v: f32 }
+/// Owns the DAC; the setpoint itself lives in `HV_SETPOINT_MV` so `safety::stop` can zero it.
+pub struct DacController;
 
 const HV_MAX_PHASE1_V: f32 = 10.0;
 const HV_MIN_V: f32 = 0.0;
 const HV_STEP_V: f32 = 0.1;
 const DAC_FULL_SCALE_V: f32 = 2.5;
 
+/// Current HV setpoint in mV: the base for steps and ramps, read by the frequency envelope checks.
+static HV_SETPOINT_MV: AtomicU32 = AtomicU32::new(0);
+
+pub fn setpoint_v() -> f32 { HV_SETPOINT_MV.load(Ordering::Acquire) as f32 / 1000.0 }
+/// Called by `safety::stop` after it has zeroed the DAC register.
+pub fn zero_setpoint() { HV_SETPOINT_MV.store(0, Ordering::Release); }
+
+/// Clamp or reject `v` against the operating envelope at the current frequency and Cin.
+fn in_envelope(v: f32) -> Result<f32, Violation> {
+    envelope::with(|e| e.check_volts(v, frequency_control::current_mhz(), hv_control::status().cin))
+}
+
 impl DacController {
-    pub fn new() -> Self { Self { hv_setpoint_v: 0.0 } }
+    pub fn new() -> Self { Self }
     fn clamp_phase1(v: f32) -> f32 { v.clamp(HV_MIN_V, HV_MAX_PHASE1_V) }
     fn hv_to_dac_volts(hv: f32) -> f32 { (hv / 300.0) * DAC_FULL_SCALE_V }
     fn hv_to_dac_raw(hv: f32) -> u16 {
@@ -22,6 +39,12 @@ impl DacController {
         let code = (v_dac / 3.0 * 4095.0).round();
         code as u16
     }
+    fn set<'d>(&mut self, dac: &mut Dac<'d, { embassy_stm32::peripherals::DAC::CHANNELS }>, v: f32) -> u16 {
+        let code = Self::hv_to_dac_raw(v);
+        HV_SETPOINT_MV.store((v * 1000.0 + 0.5) as u32, Ordering::Release); // v >= 0; rounded so steps do not drift
+        dac.set_value(DacChannel::Ch1, code);
+        code
+    }
     fn safe_ramp(current: f32, target: f32) -> f32 {
         let diff = target - current;
         if diff > HV_STEP_V { current + HV_STEP_V }
@@ -35,31 +58,39 @@ pub async fn dac_task<'d>(mut dac: Dac<'d, { embassy_stm32::peripherals::DAC::CH
     let mut ctrl = DacController::new();
     loop {
         let cmd = rx.receive().await;
+        if safety::fault_latched() && !matches!(cmd, DacCmd::SetHvVolts(v) if v <= 0.0) {
+            ctrl.set(&mut dac, 0.0);
+            warn!("DAC {} refused: fault latched", cmd);
+            continue;
+        }
         match cmd {
-            DacCmd::SetHvVolts(hv) => {
-                let target = DacController::clamp_phase1(hv);
-                ctrl.hv_setpoint_v = target;
-                let code = DacController::hv_to_dac_raw(target);
-                dac.set_value(DacChannel::Ch1, code);
-                info!("DAC HV setpoint={=f32}V (code {=u16})", target, code);
-            }
-            DacCmd::ShortStep => {
-                let target = DacController::clamp_phase1(ctrl.hv_setpoint_v + HV_STEP_V);
-                let code = DacController::hv_to_dac_raw(target);
-                ctrl.hv_setpoint_v = target;
-                dac.set_value(DacChannel::Ch1, code);
-                info!("DAC short step -> {=f32}V", target);
-            }
+            DacCmd::SetHvVolts(hv) => match in_envelope(DacController::clamp_phase1(hv)) {
+                Ok(target) => {
+                    let code = ctrl.set(&mut dac, target);
+                    info!("DAC HV setpoint={=f32}V (code {=u16})", target, code);
+                }
+                Err(e) => warn!("DAC {=f32}V refused: {}", hv, e),
+            },
+            DacCmd::ShortStep(n) => match in_envelope(DacController::clamp_phase1(setpoint_v() + HV_STEP_V * n as f32)) {
+                Ok(target) => { ctrl.set(&mut dac, target); info!("DAC short step -> {=f32}V", target); }
+                Err(e) => warn!("DAC short step refused: {}", e),
+            },
             DacCmd::StartRamp => {
                 info!("DAC ramp start");
+                let gen = safety::stop_generation();
                 for _ in 0..1000 {
-                    let target = DacController::clamp_phase1(ctrl.hv_setpoint_v + HV_STEP_V);
-                    let stepped = DacController::safe_ramp(ctrl.hv_setpoint_v, target);
-                    let code = DacController::hv_to_dac_raw(stepped);
-                    ctrl.hv_setpoint_v = stepped;
-                    dac.set_value(DacChannel::Ch1, code);
+                    if safety::stop_generation() != gen { warn!("DAC ramp aborted: stop"); break; }
+                    if safety::fault_latched() { warn!("DAC ramp aborted: fault latched"); break; }
+                    let current = setpoint_v();
+                    let target = DacController::clamp_phase1(current + HV_STEP_V);
+                    let stepped = match in_envelope(DacController::safe_ramp(current, target)) {
+                        Ok(v) if v > current => v,
+                        Ok(_) => { info!("DAC ramp stopped at envelope limit {=f32}V", current); break; }
+                        Err(e) => { warn!("DAC ramp stopped: {}", e); break; }
+                    };
+                    ctrl.set(&mut dac, stepped);
                     Timer::after_millis(500).await;
-                    if (ctrl.hv_setpoint_v - HV_MAX_PHASE1_V).abs() < 1e-6 { break; }
+                    if (setpoint_v() - HV_MAX_PHASE1_V).abs() < 1e-3 { break; }
                 }
             }
         }
@@ -0,0 +1,50 @@
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use crate::hv_control::{self, HvState};
+use crate::safety::{self, StopReason};
+use crate::Button;
+
+/* Hold-to-run: HV may only be enabled while the operator holds the designated button. A
+   debounced press arms and enables; the first raw release or the maximum hold time runs the
+   stop path from the button watcher itself, without going through a channel. While held, the
+   watcher stamps every sample; hv_task stops HV if the stamp goes stale, so a stalled watcher
+   cannot leave the output on. A new hold needs a fresh press. */
+
+/// Longest gap between watcher samples of a held button before hv_task stops HV.
+pub const WATCHDOG_MS: u32 = 50;
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub struct DeadMan { pub button: Button, pub max_hold_ms: u32 }
+
+static MODE: Mutex<CriticalSectionRawMutex, Cell<Option<DeadMan>>> = Mutex::new(Cell::new(None));
+static HELD: AtomicBool = AtomicBool::new(false);
+/// Low 32 bits of the millisecond time of the last sample that saw the button held.
+static SEEN_MS: AtomicU32 = AtomicU32::new(0);
+
+pub fn mode() -> Option<DeadMan> { MODE.lock(|m| m.get()) }
+
+/// Changing the mode with HV running stops it: the running output was not enabled under the new rule.
+pub fn set_mode(m: Option<DeadMan>) {
+    MODE.lock(|c| c.set(m));
+    HELD.store(false, Ordering::Release);
+    if hv_control::status().state == HvState::Running { safety::stop(StopReason::DeadMan); }
+    defmt::info!("Dead-man {}", m);
+}
+
+pub fn held() -> bool { HELD.load(Ordering::Acquire) }
+/// HV enable is allowed: no dead-man mode, or its button is held.
+pub fn enable_permitted() -> bool { mode().is_none() || held() }
+
+pub fn press(now_ms: u64) { refresh(now_ms); HELD.store(true, Ordering::Release); defmt::info!("Dead-man held"); }
+/// The watcher sampled the button still held at `now_ms`.
+pub fn refresh(now_ms: u64) { SEEN_MS.store(now_ms as u32, Ordering::Release); }
+/// A hold is in force but the watcher has not confirmed it within `WATCHDOG_MS`.
+pub fn stale(now_ms: u64) -> bool { held() && (now_ms as u32).wrapping_sub(SEEN_MS.load(Ordering::Acquire)) > WATCHDOG_MS }
+
+/// End the hold and run the stop path.
+pub fn release(why: &'static str) {
+    HELD.store(false, Ordering::Release);
+    safety::stop(StopReason::DeadMan);
+    defmt::info!("Dead-man stop: {=str}", why);
+}
@@ -0,0 +1,96 @@
+/* Button debouncing on raw levels and millisecond timestamps. Built and tested on the host
+   by host-tests, so it must not depend on embassy or the HAL. */
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Released,
+    /// Input went active at `at`; not yet stable.
+    Pressing { at: u64 },
+    Pressed,
+    /// Input went inactive at `at`; a bounce back returns to `Pressed`.
+    Releasing { at: u64 },
+}
+
+/// Debounce for one button. Fed with the raw level and a millisecond timestamp; gestures are
+/// decided by `GestureEngine`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Debouncer { state: State, debounce_ms: u64 }
+
+impl Debouncer {
+    pub const fn new(debounce_ms: u64) -> Self { Self { state: State::Released, debounce_ms } }
+
+    /// Feed the input (`true` = pressed) at `now_ms`; returns the new level once it is stable.
+    /// The reported press time is when the input first went active, not when it settled.
+    pub fn update(&mut self, pressed: bool, now_ms: u64) -> Option<(bool, u64)> {
+        let (next, out) = match (self.state, pressed) {
+            (State::Released, true) => (State::Pressing { at: now_ms }, None),
+            (State::Pressing { .. } | State::Released, false) => (State::Released, None),
+            (State::Pressing { at }, true) if now_ms - at >= self.debounce_ms => (State::Pressed, Some((true, at))),
+            (s @ State::Pressing { .. }, true) => (s, None),
+            (State::Pressed, false) => (State::Releasing { at: now_ms }, None),
+            (State::Pressed | State::Releasing { .. }, true) => (State::Pressed, None),
+            (State::Releasing { at }, false) if now_ms - at >= self.debounce_ms => (State::Released, Some((false, at))),
+            (s @ State::Releasing { .. }, false) => (s, None),
+        };
+        self.state = next;
+        out
+    }
+
+    /// Time at which `update` must be called again even if the input does not change.
+    pub fn deadline(&self) -> Option<u64> {
+        match self.state {
+            State::Released | State::Pressed => None,
+            State::Pressing { at } | State::Releasing { at } => Some(at + self.debounce_ms),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u64 = 30;
+
+    /// Feed `(time, level)` samples, collecting what the debouncer reports.
+    fn run(samples: &[(u64, bool)]) -> Vec<(bool, u64)> {
+        let mut d = Debouncer::new(MS);
+        samples.iter().filter_map(|&(t, l)| d.update(l, t)).collect()
+    }
+
+    #[test]
+    fn clean_press_and_release() {
+        assert_eq!(run(&[(0, true), (30, true), (100, false), (130, false)]), [(true, 0), (false, 100)]);
+    }
+
+    #[test]
+    fn bounce_on_press_restarts_the_window() {
+        // Chatter at 0..10, settles at 12: reported once, timed from the settled edge
+        let ev = run(&[(0, true), (5, false), (8, true), (10, false), (12, true), (30, true), (42, true)]);
+        assert_eq!(ev, [(true, 12)]);
+    }
+
+    #[test]
+    fn bounce_on_release_is_ignored() {
+        let ev = run(&[(0, true), (30, true), (100, false), (105, true), (110, false), (120, true), (200, true)]);
+        assert_eq!(ev, [(true, 0)]);
+        let ev = run(&[(0, true), (30, true), (100, false), (105, true), (110, false), (140, false)]);
+        assert_eq!(ev, [(true, 0), (false, 110)]);
+    }
+
+    #[test]
+    fn release_within_the_debounce_window_is_no_press() {
+        assert!(run(&[(0, true), (10, true), (20, false), (50, false), (500, false)]).is_empty());
+    }
+
+    #[test]
+    fn deadline_only_while_settling() {
+        let mut d = Debouncer::new(MS);
+        assert_eq!(d.deadline(), None);
+        d.update(true, 7);
+        assert_eq!(d.deadline(), Some(37));
+        d.update(true, 37);
+        assert_eq!(d.deadline(), None);
+        d.update(false, 50);
+        assert_eq!(d.deadline(), Some(80));
+    }
+}
@@ -23,7 +23,7 @@ impl<'d> Mcp23017<'d> {
         self.i2c.read(self.addr, out).await.map_err(|_| ())
     }
 
-    pub async fn init(mut self) -> Result<Self, ()> {
+    pub async fn init(&mut self) -> Result<(), ()> {
         // all outputs
         self.write_reg(0x00, 0x00).await?; // IODIRA
         self.write_reg(0x01, 0x00).await?; // IODIRB
@@ -33,7 +33,7 @@ impl<'d> Mcp23017<'d> {
         self.write_reg(0x0D, 0x00).await?; // GPPUB
         self.write_reg(0x14, 0x00).await?; // OLATA
         self.write_reg(0x15, 0x00).await?; // OLATB
-        Ok(self)
+        Ok(())
     }
 
     pub async fn set_gpb(&mut self, mask: u8, value: u8) -> Result<(), ()> {
@@ -54,4 +54,7 @@ impl<'d> Mcp23017<'d> {
 
     pub async fn write_gpb(&mut self, value: u8) -> Result<(), ()> { self.write_reg(0x15, value).await }
     pub async fn write_gpa(&mut self, value: u8) -> Result<(), ()> { self.write_reg(0x14, value).await }
+
+    pub async fn read_olatb(&mut self) -> Result<u8, ()> { let mut buf = [0u8]; self.read_reg(0x15, &mut buf).await?; Ok(buf[0]) }
+    pub async fn read_olata(&mut self) -> Result<u8, ()> { let mut buf = [0u8]; self.read_reg(0x14, &mut buf).await?; Ok(buf[0]) }
 }
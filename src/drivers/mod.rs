@@ -0,0 +1,4 @@
+pub mod mcp23017;
+pub mod mcp3424;
+pub mod pulse_timer;
+pub mod soft_uart;
@@ -0,0 +1,375 @@
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use embassy_stm32::gpio::OutputType;
+use embassy_stm32::interrupt;
+use embassy_stm32::interrupt::InterruptExt;
+use embassy_stm32::pac;
+use embassy_stm32::pac::timer::vals::{CcmrInputCcs, FilterValue, Ocm, Urs};
+use embassy_stm32::peripherals::{PA1, PA2, PA5, TIM2};
+use embassy_stm32::rcc::RccPeripheral;
+use embassy_stm32::timer::simple_pwm::{Ch1, Ch2, PwmPin};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+
+/* TIM2_CH1 on PA5, PWM mode 1 with ARR/CCR1 preload. PSC/ARR/CCR1 are written with UDIS set
+   so a running output only picks up new values at the next update event (period boundary).
+   Stopping forces OC1REF inactive immediately, so the pin goes low without waiting for the period.
+
+   Input capture shares the counter: TI3 (PA2) feeds IC3 on rising and IC4 on falling edges.
+   The ISR extends captures with the number of counter wraps, so the output can keep running
+   at its own period while an external signal is measured.
+
+   Pulse trains are counted in the ISR: when the update event that starts the last pulse fires,
+   CCR1 is preloaded with 0 so the following period stays low, and the update after that
+   forces the output off. The train therefore ends on a period boundary with no runt pulse.
+
+   External trigger/gate: PA2 edges (IC3 rising, IC4 falling) or a software call start the
+   stopped counter at ARR+1-delay, so the first pulse rises exactly `delay` after the edge is
+   serviced. Latency from the pin edge is the N8 input filter (~0.1 us) plus ISR entry, a few us
+   at most; the delay adds on top and is limited to one period minus the pulse width.
+
+   Marker output: TIM2_CH2 on PA1 runs PWM mode 1 off the same counter, so a pulse or burst
+   marker rises on the same timer clock as the PA5 edge (skew < 1 timer tick, 12.5 ns at
+   80 MHz PSC=0), and is never wider than the stimulus pulse. The burst marker is loaded for
+   the first period only, by writing CCR2 around the UG that starts the output. */
+
+pub const TICK_HZ: u32 = 1_000_000; // capture resolution, 1 us
+
+/// Update events (= rising edges in PWM mode 1) while the output is on; wraps.
+pub static EDGES: AtomicU32 = AtomicU32::new(0);
+static OUTPUT_ON: AtomicBool = AtomicBool::new(false);
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+/// HV interlock: set by `hv_control` outside `Running`; nothing can start the output while set.
+static INHIBIT: AtomicBool = AtomicBool::new(true);
+/// Raised with the new permission whenever the interlock changes.
+pub static PERMIT_CHANGED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+/// Pulses of the current train still to start; 0 while free-running.
+static TRAIN_LEFT: AtomicU32 = AtomicU32::new(0);
+static TRAIN_ENDING: AtomicBool = AtomicBool::new(false);
+/// Raised from the ISR once the last pulse of a train has completed.
+pub static TRAIN_DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Latest complete cycle measured on PA2, in timer ticks (1 us).
+#[derive(Copy, Clone, Debug, defmt::Format, Default)]
+pub struct Capture { pub period_ticks: u32, pub high_ticks: u32, pub count: u32 }
+
+#[derive(Copy, Clone, Default)]
+struct CaptureState { base: u64, rise: Option<u64>, fall: Option<u64>, last: Capture }
+
+/// External input behaviour: `burst` pulses per active edge, or gated (`burst` = 0) for as long
+/// as the input stays active. `pin` selects PA2; otherwise only `external_edge` drives it.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub struct ExtConfig { pub pin: bool, pub active_high: bool, pub burst: u32, pub delay_us: u32 }
+
+#[derive(Copy, Clone)]
+struct ExtArmed { cfg: ExtConfig, ccr: u32, marker: MarkerTicks, start_cnt: u32 }
+
+static EXT: Mutex<CriticalSectionRawMutex, Cell<Option<ExtArmed>>> = Mutex::new(Cell::new(None));
+
+pub const MARKER_PULSE_US: u32 = 10;
+pub const MARKER_BURST_US: u32 = 50;
+
+/// Which events pulse the PA1 marker output.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq, Default)]
+pub struct Markers { pub pulse: bool, pub burst: bool, pub polarity: bool, pub hv_enable: bool }
+
+static MARKERS: Mutex<CriticalSectionRawMutex, Cell<Markers>> =
+    Mutex::new(Cell::new(Markers { pulse: false, burst: false, polarity: false, hv_enable: false }));
+
+pub fn markers() -> Markers { MARKERS.lock(|m| m.get()) }
+/// New settings are picked up by the next `start`/`arm_external`.
+pub fn set_markers(m: Markers) { MARKERS.lock(|x| x.set(m)); }
+
+/// CCR2 values: every period, and for the first period of a burst.
+#[derive(Copy, Clone)]
+struct MarkerTicks { pulse: u32, burst: u32 }
+
+impl MarkerTicks {
+    fn new(tick_hz: u64, ccr: u32) -> Self {
+        let m = markers();
+        let ticks = |us: u32| (us as u64 * tick_hz / 1_000_000).min(ccr as u64) as u32;
+        let pulse = if m.pulse { ticks(MARKER_PULSE_US) } else { 0 };
+        Self { pulse, burst: if m.burst { ticks(MARKER_BURST_US).max(pulse) } else { pulse } }
+    }
+}
+
+static CAPTURE: Mutex<CriticalSectionRawMutex, Cell<CaptureState>> =
+    Mutex::new(Cell::new(CaptureState { base: 0, rise: None, fall: None, last: Capture { period_ticks: 0, high_ticks: 0, count: 0 } }));
+
+pub struct PulseTimer<'d> {
+    _pin: PwmPin<'d, TIM2, Ch1>,
+    _cap_pin: PA2,
+    _marker_pin: PwmPin<'d, TIM2, Ch2>,
+    running: Option<(u32, u32)>,
+}
+
+impl<'d> PulseTimer<'d> {
+    pub fn new(_tim: TIM2, pa5: PA5, pa2: PA2, pa1: PA1) -> Self {
+        TIM2::enable_and_reset();
+        let pin = PwmPin::new_ch1(pa5, OutputType::PushPull);
+        let marker_pin = PwmPin::new_ch2(pa1, OutputType::PushPull);
+        let t = pac::TIM2;
+        t.cr1().modify(|w| { w.set_arpe(true); w.set_urs(Urs::COUNTERONLY); });
+        t.ccmr_output(0).modify(|w| {
+            w.set_ocm(0, Ocm::FORCEINACTIVE); w.set_ocpe(0, true);
+            w.set_ocm(1, Ocm::FORCEINACTIVE); w.set_ocpe(1, true);
+        });
+        t.ccer().modify(|w| { w.set_cce(0, true); w.set_cce(1, true); });
+        t.dier().modify(|w| w.set_uie(true));
+        interrupt::TIM2.unpend();
+        unsafe { interrupt::TIM2.enable(); }
+        Self { _pin: pin, _cap_pin: pa2, _marker_pin: marker_pin, running: None }
+    }
+
+    /// Smallest prescaler whose 32-bit ARR still spans the period, for the finest resolution.
+    /// While capturing, PSC stays at the 1 us capture tick so both share one time base.
+    fn timing(clk_hz: u32, f_mhz: u32) -> (u32, u32) {
+        let ticks = ((clk_hz as u64 * 1000 + f_mhz as u64 / 2) / f_mhz as u64).max(2);
+        let psc = if CAPTURING.load(Ordering::Relaxed) { (clk_hz / TICK_HZ).max(1) as u64 - 1 } else { ((ticks - 1) >> 32).min(0xFFFF) };
+        let arr = ((ticks + (psc + 1) / 2) / (psc + 1)).clamp(2, u32::MAX as u64 + 1) - 1;
+        (psc as u32, arr as u32)
+    }
+
+    /// `width_us` high pulse every `1000/f_mhz` seconds; takes effect at the next period boundary
+    /// if already running. Returns the frequency actually produced, in mHz.
+    pub fn start(&mut self, f_mhz: u32, width_us: u32) -> u32 {
+        if f_mhz == 0 || INHIBIT.load(Ordering::Relaxed) { self.stop(); return 0; }
+        let clk = TIM2::frequency().0;
+        let (psc, arr) = Self::timing(clk, f_mhz);
+        let tick_hz = clk as u64 / (psc as u64 + 1);
+        // Keep the trailing low period of an ending train
+        let ccr = if TRAIN_ENDING.load(Ordering::Relaxed) { 0 } else { (width_us as u64 * tick_hz / 1_000_000).min(arr as u64) as u32 };
+        let marker = MarkerTicks::new(tick_hz, ccr);
+        let t = pac::TIM2;
+        t.cr1().modify(|w| w.set_udis(true));
+        t.psc().write_value(psc as u16);
+        t.arr().write_value(arr);
+        t.ccr(0).write_value(ccr);
+        t.ccr(1).write_value(marker.pulse);
+        t.cr1().modify(|w| w.set_udis(false));
+        // Output off (or stopped behind our back by `force_low`): load the shadow registers now
+        // and start the first period from zero; the capture history no longer lines up
+        if !OUTPUT_ON.load(Ordering::Relaxed) {
+            t.ccr(1).write_value(marker.burst);
+            t.egr().write(|w| w.set_ug(true));
+            t.ccr(1).write_value(marker.pulse);
+            CAPTURE.lock(|c| { let mut s = c.get(); s.rise = None; s.fall = None; c.set(s); });
+            set_ocm(Ocm::PWMMODE1);
+            t.cr1().modify(|w| w.set_cen(true));
+            OUTPUT_ON.store(true, Ordering::Relaxed);
+            count_edge();
+        }
+        self.running = Some((f_mhz, width_us));
+        achieved_mhz(tick_hz, arr)
+    }
+
+    /// Program the period and wait for the external input instead of starting.
+    /// Fails if the delay does not fit in one period minus the pulse width.
+    pub fn arm_external(&mut self, f_mhz: u32, width_us: u32, cfg: ExtConfig) -> Result<u32, ()> {
+        self.disarm_external();
+        let clk = TIM2::frequency().0;
+        let (psc, arr) = Self::timing(clk, f_mhz);
+        let tick_hz = clk as u64 / (psc as u64 + 1);
+        let ccr = (width_us as u64 * tick_hz / 1_000_000).min(arr as u64) as u32;
+        let delay = (cfg.delay_us as u64 * tick_hz / 1_000_000).max(1);
+        if delay > arr as u64 + 1 - ccr as u64 { return Err(()); }
+        let t = pac::TIM2;
+        t.cr1().modify(|w| w.set_udis(true));
+        t.psc().write_value(psc as u16);
+        t.arr().write_value(arr);
+        t.ccr(0).write_value(ccr);
+        t.cr1().modify(|w| w.set_udis(false));
+        if cfg.pin { input_on(); }
+        EXT.lock(|e| e.set(Some(ExtArmed { cfg, ccr, marker: MarkerTicks::new(tick_hz, ccr), start_cnt: (arr as u64 + 1 - delay) as u32 })));
+        Ok(achieved_mhz(tick_hz, arr))
+    }
+
+    pub fn disarm_external(&mut self) {
+        if let Some(x) = EXT.lock(|e| e.replace(None)) { if x.cfg.pin && !CAPTURING.load(Ordering::Relaxed) { input_off(); } }
+        Self::force_low();
+    }
+
+    /// Software edge for an armed external mode: `true` = input went active.
+    pub fn external_edge(&mut self, active: bool) { EXT.lock(|e| if let Some(x) = e.get() { ext_edge(&x, active) }); }
+
+    /// Disarm the external input and stop, from any context; used by the stop path.
+    pub fn halt() {
+        EXT.lock(|e| e.set(None));
+        Self::force_low();
+    }
+
+    /// Emit exactly `n` pulses from a stopped output, then stop and raise `TRAIN_DONE`.
+    pub fn start_train(&mut self, f_mhz: u32, width_us: u32, n: u32) -> u32 {
+        self.stop();
+        TRAIN_DONE.reset();
+        TRAIN_LEFT.store(n, Ordering::Relaxed);
+        self.start(f_mhz, width_us)
+    }
+
+    pub fn stop(&mut self) { self.disarm_external(); self.running = None; }
+
+    /// Register-only stop, safe to call from any context including the stop path.
+    /// The counter keeps running while a capture is in progress.
+    pub fn force_low() {
+        let t = pac::TIM2;
+        set_ocm(Ocm::FORCEINACTIVE);
+        OUTPUT_ON.store(false, Ordering::Relaxed);
+        TRAIN_LEFT.store(0, Ordering::Relaxed);
+        TRAIN_ENDING.store(false, Ordering::Relaxed);
+        if !CAPTURING.load(Ordering::Relaxed) { t.cr1().modify(|w| w.set_cen(false)); }
+    }
+
+    /// Drive the marker directly for events not tied to a PA5 edge; `false` hands it back to the timer.
+    pub fn marker_force(active: bool) {
+        let ocm = match (active, OUTPUT_ON.load(Ordering::Relaxed)) { (true, _) => Ocm::FORCEACTIVE, (false, true) => Ocm::PWMMODE1, (false, false) => Ocm::FORCEINACTIVE };
+        pac::TIM2.ccmr_output(0).modify(|w| w.set_ocm(1, ocm));
+    }
+
+    /// Start measuring PA2 (TIM2_CH3, AF1) on both edges.
+    pub fn enter_capture(&mut self) {
+        let t = pac::TIM2;
+        CAPTURE.lock(|c| c.set(CaptureState::default()));
+        CAPTURING.store(true, Ordering::Relaxed);
+        input_on();
+        // Re-time a running output onto the capture tick
+        if let (Some((f, w)), true) = (self.running, OUTPUT_ON.load(Ordering::Relaxed)) { self.start(f, w); }
+        if !t.cr1().read().cen() {
+            t.psc().write_value(((TIM2::frequency().0 / TICK_HZ).max(1) - 1) as u16);
+            // Output idle: free-run the counter over the full 32 bits
+            t.cr1().modify(|w| w.set_udis(true));
+            t.arr().write_value(u32::MAX);
+            t.cr1().modify(|w| w.set_udis(false));
+            t.egr().write(|w| w.set_ug(true));
+            t.cr1().modify(|w| w.set_cen(true));
+        }
+    }
+
+    pub fn exit_capture(&mut self) {
+        input_off();
+        CAPTURING.store(false, Ordering::Relaxed);
+        if !OUTPUT_ON.load(Ordering::Relaxed) { pac::TIM2.cr1().modify(|w| w.set_cen(false)); }
+        else if let Some((f, w)) = self.running { self.start(f, w); }
+    }
+
+    pub fn capture(&self) -> Capture { CAPTURE.lock(|c| c.get().last) }
+}
+
+fn achieved_mhz(tick_hz: u64, arr: u32) -> u32 { ((tick_hz * 1000 * 1000 / (arr as u64 + 1) + 500) / 1000) as u32 }
+
+/// PA2 as TI3: IC3 latches rising and IC4 falling edges, both through the N8 filter.
+fn input_on() {
+    pac::GPIOA.moder().modify(|w| w.set_moder(2, pac::gpio::vals::Moder::ALTERNATE));
+    pac::GPIOA.afr(0).modify(|w| w.set_afr(2, 1));
+    let t = pac::TIM2;
+    t.ccmr_input(1).modify(|w| {
+        w.set_ccs(0, CcmrInputCcs::from_bits(0b01)); // IC3 <- TI3
+        w.set_ccs(1, CcmrInputCcs::from_bits(0b10)); // IC4 <- TI3
+        w.set_icf(0, FilterValue::FCK_INT_N8);
+        w.set_icf(1, FilterValue::FCK_INT_N8);
+    });
+    t.ccer().modify(|w| { w.set_ccp(2, false); w.set_ccp(3, true); w.set_cce(2, true); w.set_cce(3, true); });
+    t.dier().modify(|w| { w.set_ccie(2, true); w.set_ccie(3, true); });
+}
+
+fn input_off() {
+    let t = pac::TIM2;
+    t.dier().modify(|w| { w.set_ccie(2, false); w.set_ccie(3, false); });
+    t.ccer().modify(|w| { w.set_cce(2, false); w.set_cce(3, false); });
+    pac::GPIOA.moder().modify(|w| w.set_moder(2, pac::gpio::vals::Moder::ANALOG));
+}
+
+/// Start the stopped counter so the first update, and the first pulse, come `delay` later.
+/// Both channels together: the marker follows the stimulus on and off.
+fn set_ocm(ocm: Ocm) { pac::TIM2.ccmr_output(0).modify(|w| { w.set_ocm(0, ocm); w.set_ocm(1, ocm); }); }
+
+fn ext_fire(x: &ExtArmed) {
+    if INHIBIT.load(Ordering::Relaxed) { return; }
+    let t = pac::TIM2;
+    t.ccr(0).write_value(x.ccr);
+    t.ccr(1).write_value(x.marker.burst);
+    t.egr().write(|w| w.set_ug(true));
+    t.ccr(1).write_value(x.marker.pulse);
+    t.cnt().write_value(x.start_cnt);
+    TRAIN_LEFT.store(x.cfg.burst, Ordering::Relaxed);
+    TRAIN_ENDING.store(false, Ordering::Relaxed);
+    set_ocm(Ocm::PWMMODE1);
+    OUTPUT_ON.store(true, Ordering::Relaxed);
+    t.cr1().modify(|w| w.set_cen(true));
+}
+
+fn ext_edge(x: &ExtArmed, active: bool) {
+    let (on, ending) = (OUTPUT_ON.load(Ordering::Relaxed), TRAIN_ENDING.load(Ordering::Relaxed));
+    match (x.cfg.burst, active) {
+        // Gate re-opened before the last period ran out: keep going
+        (0, true) if ending => { pac::TIM2.ccr(0).write_value(x.ccr); pac::TIM2.ccr(1).write_value(x.marker.pulse); TRAIN_ENDING.store(false, Ordering::Relaxed); }
+        // Gate closed: finish the current period, then stop
+        (0, false) if on && !ending => { end_after_period(); }
+        // A burst in progress ignores further triggers
+        (_, true) if !on => ext_fire(x),
+        _ => {}
+    }
+}
+
+/// Forces the output low at once when inhibiting; the owner re-starts it when permitted again.
+pub fn set_inhibit(on: bool) {
+    if INHIBIT.swap(on, Ordering::Relaxed) == on { return; }
+    if on { PulseTimer::force_low(); }
+    PERMIT_CHANGED.signal(!on);
+}
+
+pub fn output_permitted() -> bool { !INHIBIT.load(Ordering::Relaxed) }
+
+/// Leave the next period empty on both channels; the update after it stops the output.
+fn end_after_period() {
+    pac::TIM2.ccr(0).write_value(0);
+    pac::TIM2.ccr(1).write_value(0);
+    TRAIN_ENDING.store(true, Ordering::Relaxed);
+}
+
+/// Count a rising edge; on the last pulse of a train, make the next period the final, empty one.
+fn count_edge() {
+    EDGES.fetch_add(1, Ordering::Relaxed);
+    let left = TRAIN_LEFT.load(Ordering::Relaxed);
+    if left == 0 { return; }
+    TRAIN_LEFT.store(left - 1, Ordering::Relaxed);
+    if left == 1 { end_after_period(); }
+}
+
+#[interrupt]
+fn TIM2() {
+    let t = pac::TIM2;
+    let sr = t.sr().read();
+    let wrap = t.arr().read() as u64 + 1;
+    if sr.uif() {
+        // SR flags are rc_w0: write ones everywhere but UIF so a capture flag set since the read survives
+        t.sr().write(|w| { w.0 = !0; w.set_uif(false) });
+        if TRAIN_ENDING.load(Ordering::Relaxed) { PulseTimer::force_low(); TRAIN_DONE.signal(()); }
+        else if OUTPUT_ON.load(Ordering::Relaxed) { count_edge(); }
+    }
+    if let Some(x) = EXT.lock(|e| e.get()) {
+        if !(sr.ccif(2) || sr.ccif(3)) { return; }
+        let _ = (t.ccr(2).read(), t.ccr(3).read()); // clears CC3IF/CC4IF
+        // Edges closer together than the ISR latency: go by the pin level now
+        let active = (pac::GPIOA.idr().read().idr(2) == pac::gpio::vals::Idr::HIGH) == x.cfg.active_high;
+        let active_edge = if x.cfg.active_high { sr.ccif(2) } else { sr.ccif(3) };
+        if x.cfg.burst == 0 { ext_edge(&x, active) } else if active_edge { ext_edge(&x, true) }
+        return;
+    }
+    CAPTURE.lock(|c| {
+        let mut s = c.get();
+        // A capture taken just after a wrap that is handled in this same ISR belongs to the new base
+        let base = s.base;
+        let stamp = |ccr: u32| if sr.uif() && (ccr as u64) < wrap / 2 { base + wrap + ccr as u64 } else { base + ccr as u64 };
+        if sr.ccif(3) { s.fall = Some(stamp(t.ccr(3).read())); }
+        if sr.ccif(2) {
+            let rise = stamp(t.ccr(2).read());
+            if let Some(prev) = s.rise {
+                let high = match s.fall { Some(f) if f > prev && f < rise => (f - prev) as u32, _ => 0 };
+                s.last = Capture { period_ticks: (rise - prev) as u32, high_ticks: high, count: s.last.count.wrapping_add(1) };
+            }
+            s.rise = Some(rise);
+        }
+        if sr.uif() { s.base += wrap; }
+        c.set(s);
+    });
+}
@@ -0,0 +1,103 @@
+use core::cell::Cell;
+use embassy_stm32::gpio::{Input, Level, Output, Pull, Speed};
+use embassy_stm32::interrupt;
+use embassy_stm32::interrupt::{InterruptExt, Priority};
+use embassy_stm32::pac;
+use embassy_stm32::peripherals::{PA11, PA8, TIM7};
+use embassy_stm32::rcc::RccPeripheral;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::pipe::Pipe;
+
+/* Service UART, 8N1, on PA8 (TX) and PA11 (RX). Every hardware USART TX pin of the L432KC
+   (PA2, PA9, PB6) is taken on this board, so the line is driven from the TIM7 update ISR at
+   three ticks per bit. RX waits for a low sample, then reads each bit four ticks after the
+   start was seen and every three ticks after that, which lands within the middle third of the
+   bit. TIM7 runs below TIM2's priority so the stimulus ISR is never delayed by it. */
+
+pub const BAUD: u32 = 9600;
+const TICKS_PER_BIT: u8 = 3;
+const BUF: usize = 64;
+
+static TX: Pipe<CriticalSectionRawMutex, BUF> = Pipe::new();
+static RX: Pipe<CriticalSectionRawMutex, BUF> = Pipe::new();
+
+#[derive(Copy, Clone)]
+struct State {
+    /// Remaining frame bits, LSB first; `tx_left` of them still to send.
+    tx_frame: u16,
+    tx_left: u8,
+    tx_tick: u8,
+    /// Ticks to the next RX sample; 0 while idle.
+    rx_wait: u8,
+    rx_bits: u8,
+    rx_byte: u8,
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, Cell<State>> =
+    Mutex::new(Cell::new(State { tx_frame: 0, tx_left: 0, tx_tick: 0, rx_wait: 0, rx_bits: 0, rx_byte: 0 }));
+
+pub struct SoftUart<'d> {
+    _tx: Output<'d, PA8>,
+    _rx: Input<'d, PA11>,
+}
+
+impl<'d> SoftUart<'d> {
+    pub fn new(_tim: TIM7, pa8: PA8, pa11: PA11) -> Self {
+        let tx = Output::new(pa8, Level::High, Speed::Low);
+        let rx = Input::new(pa11, Pull::Up);
+        TIM7::enable_and_reset();
+        let t = pac::TIM7;
+        t.arr().write(|w| w.set_arr((TIM7::frequency().0 / (BAUD * TICKS_PER_BIT as u32) - 1) as u16));
+        t.dier().modify(|w| w.set_uie(true));
+        t.cr1().modify(|w| w.set_cen(true));
+        interrupt::TIM7.set_priority(Priority::P1);
+        interrupt::TIM7.unpend();
+        unsafe { interrupt::TIM7.enable(); }
+        Self { _tx: tx, _rx: rx }
+    }
+
+    /// Queue `buf` for sending; waits only while the TX buffer is full.
+    pub async fn write(&mut self, buf: &[u8]) { TX.write_all(buf).await }
+
+    pub async fn read_byte(&mut self) -> u8 {
+        let mut b = [0u8; 1];
+        RX.read(&mut b).await;
+        b[0]
+    }
+}
+
+#[interrupt]
+fn TIM7() {
+    pac::TIM7.sr().write(|w| w.set_uif(false));
+    let gpio = pac::GPIOA;
+    let rx_high = gpio.idr().read().idr(11) == pac::gpio::vals::Idr::HIGH;
+    STATE.lock(|c| {
+        let mut s = c.get();
+        if s.tx_left == 0 {
+            let mut b = [0u8; 1];
+            // Start bit low, stop bit high
+            if TX.try_read(&mut b).is_ok() { s.tx_frame = (b[0] as u16) << 1 | 1 << 9; s.tx_left = 10; s.tx_tick = 0; }
+        }
+        if s.tx_left > 0 {
+            if s.tx_tick == 0 { gpio.bsrr().write(|w| if s.tx_frame & 1 != 0 { w.set_bs(8, true) } else { w.set_br(8, true) }); }
+            s.tx_tick += 1;
+            if s.tx_tick == TICKS_PER_BIT { s.tx_tick = 0; s.tx_frame >>= 1; s.tx_left -= 1; }
+        }
+        if s.rx_wait == 0 {
+            if !rx_high { s.rx_wait = TICKS_PER_BIT + 1; s.rx_bits = 0; s.rx_byte = 0; }
+        } else {
+            s.rx_wait -= 1;
+            if s.rx_wait == 0 {
+                if s.rx_bits < 8 {
+                    s.rx_byte |= (rx_high as u8) << s.rx_bits;
+                    s.rx_bits += 1;
+                    s.rx_wait = TICKS_PER_BIT;
+                } else if rx_high {
+                    // Valid stop bit; a framing error drops the byte. A full buffer drops it too.
+                    let _ = RX.try_write(&[s.rx_byte]);
+                }
+            }
+        }
+        c.set(s);
+    });
+}
@@ -0,0 +1,116 @@
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::Vec;
+use crate::hv_control::CinRange;
+
+/* Safe operating envelope of the output stage: the highest HV setpoint allowed in each
+   frequency band, optionally derated for the selected input capacitance, and the highest pulse
+   rate each capacitance can be recharged at. dac_control checks voltage requests against the
+   current frequency, frequency_control checks frequency requests against the current setpoint,
+   so neither side can walk the other out of the envelope. */
+
+pub const MAX_BANDS: usize = 8;
+
+/// Up to and including `max_freq_mhz`, the setpoint may be at most `max_v`.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub struct Band { pub max_freq_mhz: u32, pub max_v: f32 }
+
+/// What happens to a request outside the envelope.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum Action { Clamp, Reject }
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum Violation {
+    /// Setpoint above the band limit for the current frequency.
+    VoltageTooHigh { max_v: f32, freq_mhz: u32 },
+    /// Frequency too high for the current setpoint.
+    FrequencyTooHigh { max_mhz: u32, volts: f32 },
+}
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum EnvelopeError { TooManyBands, NotMonotonic, BadIndex }
+
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    /// Ascending in frequency, non-increasing in voltage. Above the last band only 0 V is allowed.
+    bands: Vec<Band, MAX_BANDS>,
+    /// Per-Cin derating of `max_v` in permille, applied when `cin_dependent` is set.
+    pub cin_permille: [u16; 4],
+    pub cin_dependent: bool,
+    /// Highest pulse rate per Cin range, always applied. The defaults halve with each step up in
+    /// capacitance from 400 Hz; they are provisional until the bank recharge times are measured.
+    pub cin_max_mhz: [u32; 4],
+    pub action: Action,
+}
+
+impl Envelope {
+    const fn default_const() -> Self {
+        Self { bands: Vec::new(), cin_permille: [1000, 800, 600, 400], cin_dependent: false, cin_max_mhz: [400_000, 200_000, 100_000, 50_000], action: Action::Clamp }
+    }
+    fn cin_index(cin: CinRange) -> usize {
+        match cin { CinRange::Base => 0, CinRange::Bank1 => 1, CinRange::Bank2 => 2, CinRange::Both => 3 }
+    }
+    fn derate(&self, cin: CinRange) -> f32 {
+        if self.cin_dependent { self.cin_permille[Self::cin_index(cin)] as f32 / 1000.0 } else { 1.0 }
+    }
+    pub fn cin_max_mhz(&self, cin: CinRange) -> u32 { self.cin_max_mhz[Self::cin_index(cin)] }
+    pub fn bands(&self) -> &[Band] { &self.bands }
+    pub fn set_bands(&mut self, bands: &[Band]) -> Result<(), EnvelopeError> {
+        if bands.windows(2).any(|w| w[1].max_freq_mhz <= w[0].max_freq_mhz || w[1].max_v > w[0].max_v) { return Err(EnvelopeError::NotMonotonic); }
+        self.bands = Vec::from_slice(bands).map_err(|_| EnvelopeError::TooManyBands)?;
+        Ok(())
+    }
+    /// Highest setpoint allowed at `freq_mhz`; a stopped output (0) is not limited.
+    pub fn max_volts(&self, freq_mhz: u32, cin: CinRange) -> f32 {
+        if freq_mhz == 0 { return f32::MAX; }
+        self.bands.iter().find(|b| freq_mhz <= b.max_freq_mhz).map_or(0.0, |b| b.max_v * self.derate(cin))
+    }
+    /// Highest frequency allowed at `volts`; 0 V is not limited.
+    pub fn max_freq_mhz(&self, volts: f32, cin: CinRange) -> u32 {
+        if volts <= 0.0 { return u32::MAX; }
+        let k = self.derate(cin);
+        self.bands.iter().filter(|b| volts <= b.max_v * k).map(|b| b.max_freq_mhz).max().unwrap_or(0)
+    }
+    /// Highest frequency and, at that frequency, highest setpoint the running stage may keep
+    /// after an edit to the envelope.
+    pub fn live_limits(&self, freq_mhz: u32, cin: CinRange) -> (u32, f32) {
+        let f = freq_mhz.min(self.cin_max_mhz(cin));
+        (f, self.max_volts(f, cin))
+    }
+    /// Clamp or reject a voltage request at the current frequency.
+    pub fn check_volts(&self, volts: f32, freq_mhz: u32, cin: CinRange) -> Result<f32, Violation> {
+        let max_v = self.max_volts(freq_mhz, cin);
+        if volts <= max_v { return Ok(volts); }
+        let v = Violation::VoltageTooHigh { max_v, freq_mhz };
+        match self.action { Action::Clamp => { defmt::warn!("Envelope: {}, clamped", v); Ok(max_v) } Action::Reject => Err(v) }
+    }
+    /// Clamp or reject a frequency request at the current setpoint and Cin.
+    pub fn check_freq(&self, freq_mhz: u32, volts: f32, cin: CinRange) -> Result<u32, Violation> {
+        let max_mhz = self.max_freq_mhz(volts, cin).min(self.cin_max_mhz(cin));
+        if freq_mhz <= max_mhz { return Ok(freq_mhz); }
+        let v = Violation::FrequencyTooHigh { max_mhz, volts };
+        match self.action { Action::Clamp if max_mhz > 0 => { defmt::warn!("Envelope: {}, clamped", v); Ok(max_mhz) } _ => Err(v) }
+    }
+}
+
+impl defmt::Format for Envelope {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=[?]} cin={=bool} {=[u16]} max={=[u32]} {}", self.bands.as_slice(), self.cin_dependent, &self.cin_permille[..], &self.cin_max_mhz[..], self.action)
+    }
+}
+
+/// Provisional stage limits, 10 V up to 50 Hz falling to 1 V at the 1 kHz ceiling: placeholders
+/// until the stage is characterised, not measured values. Note they hold the 60-200 Hz presets
+/// to 5 V and 400 Hz to 2 V; widen them with `env band` once the real limits are known.
+pub const DEFAULT_BANDS: [Band; 4] = [
+    Band { max_freq_mhz: 50_000, max_v: 10.0 },
+    Band { max_freq_mhz: 200_000, max_v: 5.0 },
+    Band { max_freq_mhz: 400_000, max_v: 2.0 },
+    Band { max_freq_mhz: 1_000_000, max_v: 1.0 },
+];
+
+static ENVELOPE: Mutex<CriticalSectionRawMutex, RefCell<Envelope>> = Mutex::new(RefCell::new(Envelope::default_const()));
+
+pub fn init() { with_mut(|e| { let _ = e.set_bands(&DEFAULT_BANDS); }); }
+pub fn with<R>(f: impl FnOnce(&Envelope) -> R) -> R { ENVELOPE.lock(|e| f(&e.borrow())) }
+pub fn with_mut<R>(f: impl FnOnce(&mut Envelope) -> R) -> R { ENVELOPE.lock(|e| f(&mut e.borrow_mut())) }
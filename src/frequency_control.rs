@@ -1,51 +1,725 @@
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use defmt::*;
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Timer};
+use embassy_futures::select::{select4, Either4};
 use embassy_sync::channel::mpmc::Channel;
-use embassy_stm32::gpio::{Output, Level, Speed};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
 use embassy_stm32::peripherals::TIM2;
-use crate::hv_control::HvCommand;
+use heapless::Vec;
+use crate::drivers::pulse_timer::{self, ExtConfig, Markers, PulseTimer, TICK_HZ};
+use crate::hv_control::{self, MAX_DUTY_PERMILLE, MIN_PULSE_WIDTH_US};
+use crate::dac_control;
+use crate::envelope::{self, Violation};
+use crate::safety::{self, StopReason};
+use crate::storage::{self, Region};
 
 #[derive(Copy, Clone, Debug, defmt::Format)]
-pub enum FrequencyCmd { CycleNext, EnterInputCaptureMode, SetFrequency(u32) }
+pub enum FrequencyCmd { CycleNext, CyclePrev, Preset(PresetCmd), EnterInputCaptureMode, ExitInputCaptureMode, SetCaptureSlave(bool), SetFrequency(u32) /* mHz */, SetPulseWidthUs(u32), SetDutyPermille(u32), StartTrain(TrainMode), StopTrain, SetTrigger(Option<ExtTrigger>), SoftTrigger(bool), StartSweep(Sweep), StopSweep, SetLoopback(bool), SetMarkers(Markers) }
 
-pub struct FrequencyControl { idx: usize, table: [u32; 12], freq_hz: u32 }
+impl FrequencyCmd {
+    /// Commands that pick a new rate end a running pulse train first.
+    fn ends_train(&self) -> bool {
+        matches!(self, FrequencyCmd::CycleNext | FrequencyCmd::CyclePrev | FrequencyCmd::SetFrequency(_) | FrequencyCmd::Preset(PresetCmd::Recall(_)))
+    }
+}
+
+/// Edits to the preset table; they take effect immediately and persist only after `Save`.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub enum PresetCmd { List, Add(u32) /* mHz */, Set(u8, u32), Remove(u8), Clear, Recall(u8), IncludeOff(bool), Wrap(bool), Save, Defaults }
+
+/// Finite output programs at the current frequency; `bursts`/`cycles` of 0 repeat until `StopTrain`.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum TrainMode {
+    /// `n` pulses, then stop.
+    Pulses(u32),
+    /// `bursts` trains of `pulses`, separated by `gap_ms` of silence.
+    Bursts { pulses: u32, gap_ms: u32, bursts: u32 },
+    /// Free-running for `on_s`, silent for `off_s`, `cycles` times.
+    OnOff { on_s: u32, off_s: u32, cycles: u32 },
+}
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum SweepScale { Linear, Log }
+
+/// Either a fixed number of steps each held for `dwell_ms` (`SWEEP_STEP_MS` if not given), or a
+/// total duration split into `SWEEP_STEP_MS` steps, held longer once the step limit is reached.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum SweepLength { Steps { count: u16, dwell_ms: Option<u32> }, DurationMs(u32) }
+
+/// Frequency sweep from `start_mhz` to `stop_mhz` (either direction); `repeat` restarts at `start_mhz`.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub struct Sweep { pub start_mhz: u32, pub stop_mhz: u32, pub scale: SweepScale, pub length: SweepLength, pub repeat: bool }
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum SweepError { OutOfRange, BadLength, HvNotRunning, Busy }
+
+const SWEEP_STEP_MS: u32 = 100;
+const SWEEP_MIN_DWELL_MS: u32 = 10;
+const SWEEP_MAX_STEPS: u32 = 10_000;
+
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct SweepProgress { pub sweep: Sweep, pub step: u32, pub steps: u32, pub freq_mhz: u32, pub passes: u32 }
+
+static SWEEP: Mutex<CriticalSectionRawMutex, Cell<Option<SweepProgress>>> = Mutex::new(Cell::new(None));
+
+pub fn sweep_progress() -> Option<SweepProgress> { SWEEP.lock(|s| s.get()) }
+
+/// Where the external trigger comes from: PA2 (shared with input capture) or `SoftTrigger`.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum TriggerSource { Pa2, Software }
+
+/// Gated: pulses run while the input is active. Pulses(n): each active edge fires n pulses.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum TriggerMode { Gated, Pulses(u32) }
+
+/// External synchronisation of the output. `active_high` selects the active level for gating
+/// or the rising edge for triggering; the first pulse follows the edge by `delay_us`.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub struct ExtTrigger { pub source: TriggerSource, pub mode: TriggerMode, pub active_high: bool, pub delay_us: u32 }
+
+impl ExtTrigger {
+    fn config(&self) -> ExtConfig {
+        let burst = match self.mode { TriggerMode::Gated => 0, TriggerMode::Pulses(n) => n };
+        ExtConfig { pin: self.source == TriggerSource::Pa2, active_high: self.active_high, burst, delay_us: self.delay_us }
+    }
+}
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum TriggerError { NoFrequency, FaultLatched, Busy, BadDelay, BadProgram }
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum TrainError { NoFrequency, FaultLatched, HvNotRunning, Busy, BadProgram }
+
+/// Accepted output range in mHz (0 stops the output).
+pub const MIN_FREQ_MHZ: u32 = 10;
+pub const MAX_FREQ_MHZ: u32 = 1_000_000;
+
+const CAPTURE_POLL_MS: u64 = 50;
+const CAPTURE_TIMEOUT_MS: u64 = 2_000; // or three measured periods, whichever is longer
+const CAPTURE_MIN_HZ: u32 = 1;
+const CAPTURE_MAX_HZ: u32 = 1_000;
+const CAPTURE_SAMPLES: usize = 5;
+
+const LOOPBACK_POLL_MS: u64 = 200;
+const LOOPBACK_STUCK_MS: u64 = 1_000; // or three commanded periods, whichever is longer
+const LOOPBACK_TOL_PERMILLE: u64 = 20;
+const LOOPBACK_STRIKES: u8 = 3; // consecutive out-of-tolerance checks before faulting
+
+/// Why the loopback check stopped the output.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum LoopbackFault { Stuck, Deviation { expected_mhz: u32, measured_mhz: u32 } }
+
+/// Edges seen on PA2 against edges commanded on PA5 since the check was enabled.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq, Default)]
+pub struct LoopbackStatus { pub commanded: u32, pub delivered: u32, pub measured_mhz: u32 }
+
+static LOOPBACK: Mutex<CriticalSectionRawMutex, Cell<Option<LoopbackStatus>>> = Mutex::new(Cell::new(None));
+
+pub fn loopback() -> Option<LoopbackStatus> { LOOPBACK.lock(|l| l.get()) }
+
+/// External signal on PA2 after median filtering.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub struct Measured { pub freq_mhz: u32, pub duty_permille: u32 }
+
+pub static MEASURED: Mutex<CriticalSectionRawMutex, Cell<Option<Measured>>> = Mutex::new(Cell::new(None));
+static CAPTURE_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Pulse train state published by `frequency_task`; `pulses` is completed phases only.
+#[derive(Copy, Clone, Debug)]
+struct TrainStatus { mode: TrainMode, on: bool, pulses: u32, mark: u32, cycles: u32 }
+
+static TRAIN: Mutex<CriticalSectionRawMutex, Cell<Option<TrainStatus>>> = Mutex::new(Cell::new(None));
+
+/// A finite pulse train ran to completion; hv_task turns HV off. A signal rather than a command
+/// so it can neither block frequency_task nor be lost to a full queue.
+pub static TRAIN_COMPLETE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct TrainProgress {
+    pub mode: TrainMode,
+    /// Output currently emitting (pulses or the on-time) rather than in a gap.
+    pub on: bool,
+    pub pulses_delivered: u32,
+    /// Bursts or on/off cycles still to run including the current one; `None` if unbounded.
+    pub remaining: Option<u32>,
+}
+
+pub fn train_progress() -> Option<TrainProgress> {
+    TRAIN.lock(|t| t.get()).map(|t| {
+        let live = if t.on { pulses().wrapping_sub(t.mark) } else { 0 };
+        let total = match t.mode { TrainMode::Pulses(_) => 1, TrainMode::Bursts { bursts, .. } => bursts, TrainMode::OnOff { cycles, .. } => cycles };
+        TrainProgress { mode: t.mode, on: t.on, pulses_delivered: t.pulses + live, remaining: (total > 0).then(|| total.saturating_sub(t.cycles)) }
+    })
+}
+
+pub fn measured() -> Option<Measured> { MEASURED.lock(|m| m.get()) }
+pub fn capture_active() -> bool { CAPTURE_ACTIVE.load(Ordering::Relaxed) }
+
+struct CaptureMode { slave: bool, resume_mhz: u32, periods: [u32; CAPTURE_SAMPLES], highs: [u32; CAPTURE_SAMPLES], n: usize, seen: u32, last_seen: Instant }
+
+impl CaptureMode {
+    fn new(resume_mhz: u32) -> Self { Self { slave: false, resume_mhz, periods: [0; CAPTURE_SAMPLES], highs: [0; CAPTURE_SAMPLES], n: 0, seen: 0, last_seen: Instant::now() } }
+    /// Keep samples inside the accepted range; out-of-range cycles are treated as noise.
+    fn push(&mut self, c: pulse_timer::Capture) {
+        if c.period_ticks < TICK_HZ / CAPTURE_MAX_HZ || c.period_ticks > TICK_HZ / CAPTURE_MIN_HZ { return; }
+        let i = self.n % CAPTURE_SAMPLES;
+        self.periods[i] = c.period_ticks; self.highs[i] = c.high_ticks; self.n += 1;
+    }
+    fn filtered(&self) -> Option<Measured> {
+        if self.n < CAPTURE_SAMPLES { return None; }
+        let (mut p, mut h) = (self.periods, self.highs);
+        p.sort_unstable(); h.sort_unstable();
+        let (period, high) = (p[CAPTURE_SAMPLES / 2], h[CAPTURE_SAMPLES / 2]);
+        let freq_mhz = ((TICK_HZ as u64 * 1000 + period as u64 / 2) / period as u64) as u32;
+        Some(Measured { freq_mhz, duty_permille: high * 1000 / period })
+    }
+    fn timeout(&self) -> Duration {
+        let period_ms = self.filtered().map_or(0, |m| 3_000_000 / m.freq_mhz.max(1)) as u64;
+        Duration::from_millis(CAPTURE_TIMEOUT_MS.max(period_ms))
+    }
+}
+
+/// Output verification through PA2 wired back from PA5 (or tapped from the stage output).
+/// Capture runs continuously; every poll compares delivered against commanded edges and, in
+/// steady continuous output, the measured against the achieved frequency. The output counts
+/// as stuck once more edges are owed than at the last capture and none has arrived for
+/// `max(LOOPBACK_STUCK_MS, 3 periods)` since the deficit grew; idle polls do not restart that clock.
+struct Loopback { edges0: u32, count0: u32, seen: u32, owed: i32, owing_since: Option<Instant>, strikes: u8, status: LoopbackStatus }
+
+impl Loopback {
+    fn new(out: &PulseTimer) -> Self {
+        let (e, c) = (pulses(), out.capture().count);
+        Self { edges0: e, count0: c, seen: c, owed: 0, owing_since: None, strikes: 0, status: LoopbackStatus::default() }
+    }
+    fn check(&mut self, out: &PulseTimer, steady: bool) -> Result<(), LoopbackFault> {
+        let (now, edges, cap) = (Instant::now(), pulses(), out.capture());
+        self.status.commanded = edges.wrapping_sub(self.edges0);
+        self.status.delivered = cap.count.wrapping_sub(self.count0);
+        let owed = self.status.commanded.wrapping_sub(self.status.delivered) as i32;
+        if cap.count != self.seen { self.seen = cap.count; self.owed = owed; self.owing_since = None; }
+        else if owed > self.owed && self.owing_since.is_none() { self.owing_since = Some(now); }
+        if cap.period_ticks > 0 { self.status.measured_mhz = ((TICK_HZ as u64 * 1000 + cap.period_ticks as u64 / 2) / cap.period_ticks as u64) as u32; }
+        LOOPBACK.lock(|l| l.set(Some(self.status)));
+        let period_ms = 3_000_000 / achieved_mhz().max(1) as u64;
+        if self.owing_since.is_some_and(|t| now - t > Duration::from_millis(LOOPBACK_STUCK_MS.max(period_ms))) { return Err(LoopbackFault::Stuck); }
+        let expected = achieved_mhz();
+        if !steady || expected == 0 || cap.period_ticks == 0 { self.strikes = 0; return Ok(()); }
+        let m = self.status.measured_mhz;
+        if (m as u64).abs_diff(expected as u64) * 1000 > expected as u64 * LOOPBACK_TOL_PERMILLE { self.strikes += 1; } else { self.strikes = 0; }
+        if self.strikes >= LOOPBACK_STRIKES { return Err(LoopbackFault::Deviation { expected_mhz: expected, measured_mhz: m }); }
+        Ok(())
+    }
+}
+
+/// High time of each pulse, either fixed or tracking the period.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum PulseWidth { Micros(u32), DutyPermille(u32) }
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum WidthError { TooShort, DutyTooHigh }
+
+/// Last frequency requested from the timer, read by the HV enable checks.
+pub static FREQ_MHZ: AtomicU32 = AtomicU32::new(0);
+/// What the timer actually produces for `FREQ_MHZ` after PSC/ARR quantisation.
+pub static ACHIEVED_MHZ: AtomicU32 = AtomicU32::new(0);
+
+pub fn current_mhz() -> u32 { FREQ_MHZ.load(Ordering::Relaxed) }
+pub fn achieved_mhz() -> u32 { ACHIEVED_MHZ.load(Ordering::Relaxed) }
+
+fn in_range(f: u32) -> bool { f == 0 || (MIN_FREQ_MHZ..=MAX_FREQ_MHZ).contains(&f) }
+
+/// Rising edges emitted on PA5 since boot; wraps.
+pub fn pulses() -> u32 { pulse_timer::EDGES.load(Ordering::Relaxed) }
+
+/// Clamp or reject against the operating envelope, Cin rate limit included, at the current HV setpoint.
+fn limited(f: u32) -> Result<u32, Violation> {
+    let cin = hv_control::status().cin;
+    envelope::with(|e| e.check_freq(f, dac_control::setpoint_v(), cin))
+}
+
+pub const MAX_PRESETS: usize = 16;
+const DEFAULT_PRESETS: [u32; 10] = [1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 60_000, 100_000, 200_000, 400_000];
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum PresetError { Full, BadIndex, OutOfRange }
+
+/// Frequencies stepped through by CycleNext/CyclePrev. With `include_off` the cycle has one
+/// extra "off" position after the last preset; without `wrap` it stops at either end.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PresetTable { pub freqs: Vec<u32, MAX_PRESETS>, pub include_off: bool, pub wrap: bool }
+
+impl defmt::Format for PresetTable {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=[u32]} mHz off={=bool} wrap={=bool}", self.freqs.as_slice(), self.include_off, self.wrap)
+    }
+}
+
+impl Default for PresetTable {
+    fn default() -> Self { Self { freqs: Vec::from_slice(&DEFAULT_PRESETS).unwrap(), include_off: true, wrap: true } }
+}
+
+impl PresetTable {
+    /// [count u8][flags u8][reserved u16][MAX_PRESETS x u32 mHz], little endian.
+    pub const BYTES: usize = 4 + MAX_PRESETS * 4;
+
+    fn check(f: u32) -> Result<(), PresetError> { if f != 0 && in_range(f) { Ok(()) } else { Err(PresetError::OutOfRange) } }
+    /// Cycle positions: one per preset, plus "off".
+    fn positions(&self) -> usize { self.freqs.len() + self.include_off as usize }
+    fn at(&self, pos: usize) -> u32 { self.freqs.get(pos).copied().unwrap_or(0) }
+    pub fn add(&mut self, f: u32) -> Result<(), PresetError> { Self::check(f)?; self.freqs.push(f).map_err(|_| PresetError::Full) }
+    pub fn set(&mut self, i: usize, f: u32) -> Result<(), PresetError> {
+        Self::check(f)?;
+        *self.freqs.get_mut(i).ok_or(PresetError::BadIndex)? = f; Ok(())
+    }
+    pub fn remove(&mut self, i: usize) -> Result<(), PresetError> {
+        if i >= self.freqs.len() { return Err(PresetError::BadIndex); }
+        self.freqs.remove(i); Ok(())
+    }
+    pub fn to_bytes(&self) -> [u8; Self::BYTES] {
+        let mut out = [0u8; Self::BYTES];
+        out[0] = self.freqs.len() as u8;
+        out[1] = self.include_off as u8 | (self.wrap as u8) << 1;
+        for (i, f) in self.freqs.iter().enumerate() { out[4 + i * 4..8 + i * 4].copy_from_slice(&f.to_le_bytes()); }
+        out
+    }
+    /// Rejects a record with a bad count or any preset outside the accepted range.
+    pub fn from_bytes(b: &[u8; Self::BYTES]) -> Option<Self> {
+        let n = b[0] as usize;
+        if n > MAX_PRESETS { return None; }
+        let mut t = Self { freqs: Vec::new(), include_off: b[1] & 1 != 0, wrap: b[1] & 2 != 0 };
+        for i in 0..n { t.add(u32::from_le_bytes([b[4 + i * 4], b[5 + i * 4], b[6 + i * 4], b[7 + i * 4]])).ok()?; }
+        Some(t)
+    }
+}
+
+pub struct FrequencyControl { idx: usize, presets: PresetTable, freq_mhz: u32, width: PulseWidth, ext: Option<ExtTrigger> }
 impl FrequencyControl {
-    pub fn new() -> Self {
-        Self { idx: 0, table: [1,2,5,10,20,50,60,100,200,400,0,0], freq_hz: 1 }
+    pub fn new(presets: PresetTable) -> Self {
+        let freq_mhz = presets.at(0);
+        Self { idx: 0, presets, freq_mhz, width: PulseWidth::DutyPermille(MAX_DUTY_PERMILLE), ext: None }
+    }
+    fn period_us(f: u32) -> u32 { (1_000_000_000u64 / f.max(1) as u64) as u32 }
+    /// `permille` of `period_us`; in u64 since slow periods times permille overflow u32.
+    fn duty_us(period_us: u32, permille: u32) -> u32 { (period_us as u64 * permille as u64 / 1000) as u32 }
+    fn check_width(f: u32, us: u32) -> Result<(), WidthError> {
+        if us < MIN_PULSE_WIDTH_US { return Err(WidthError::TooShort); }
+        if f > 0 && us as u64 * 1000 > Self::period_us(f) as u64 * MAX_DUTY_PERMILLE as u64 { return Err(WidthError::DutyTooHigh); }
+        Ok(())
+    }
+    pub fn set_width(&mut self, w: PulseWidth) -> Result<(), WidthError> {
+        match w {
+            PulseWidth::Micros(us) => Self::check_width(self.freq_mhz, us)?,
+            PulseWidth::DutyPermille(d) => {
+                if d > MAX_DUTY_PERMILLE { return Err(WidthError::DutyTooHigh); }
+                if self.freq_mhz > 0 && Self::duty_us(Self::period_us(self.freq_mhz), d) < MIN_PULSE_WIDTH_US { return Err(WidthError::TooShort); }
+            }
+        }
+        self.width = w; Ok(())
+    }
+    /// Pulse width for the current frequency, clamped into the stage limits.
+    pub fn width_us(&self) -> u32 {
+        let period = Self::period_us(self.freq_mhz);
+        let us = match self.width { PulseWidth::Micros(us) => us, PulseWidth::DutyPermille(d) => Self::duty_us(period, d) };
+        us.clamp(MIN_PULSE_WIDTH_US, Self::duty_us(period, MAX_DUTY_PERMILLE).max(MIN_PULSE_WIDTH_US))
+    }
+    /// Step through the presets; at an end either wrap or stay put.
+    pub fn next(&mut self) -> u32 { self.step(true) }
+    pub fn prev(&mut self) -> u32 { self.step(false) }
+    fn step(&mut self, up: bool) -> u32 {
+        let n = self.presets.positions();
+        if n == 0 { self.freq_mhz = 0; return 0; }
+        let last = n - 1;
+        self.idx = match (up, self.idx.min(last)) {
+            (true, i) if i < last => i + 1,
+            (false, i) if i > 0 => i - 1,
+            (_, i) if !self.presets.wrap => i,
+            (true, _) => 0,
+            (false, _) => last,
+        };
+        self.freq_mhz = self.presets.at(self.idx);
+        self.freq_mhz
+    }
+    pub fn recall(&mut self, i: usize) -> Result<u32, PresetError> {
+        let f = *self.presets.freqs.get(i).ok_or(PresetError::BadIndex)?;
+        self.idx = i; self.freq_mhz = f; Ok(f)
+    }
+    pub fn presets(&self) -> &PresetTable { &self.presets }
+    pub fn presets_mut(&mut self) -> &mut PresetTable { &mut self.presets }
+    pub fn set(&mut self, f: u32) -> u32 { self.freq_mhz = f; f }
+    pub fn current(&self) -> u32 { self.freq_mhz }
+}
+
+fn apply(out: &mut PulseTimer, ctrl: &FrequencyControl) {
+    let f = ctrl.current();
+    FREQ_MHZ.store(f, Ordering::Relaxed);
+    if FrequencyControl::check_width(f, ctrl.width_us()).is_err() { warn!("Pulse width {} clamped at {=u32} mHz", ctrl.width, f); }
+    let achieved = match ctrl.ext {
+        _ if f == 0 || safety::fault_latched() => { out.stop(); 0 }
+        // Interlocked with hv_task: the setpoint is kept and output starts on entering Running
+        _ if !pulse_timer::output_permitted() => {
+            out.stop();
+            warn!("Output start refused at {=u32} mHz: HV {}, held until Running", f, hv_control::status().state);
+            0
+        }
+        None => out.start(f, ctrl.width_us()),
+        Some(x) => out.arm_external(f, ctrl.width_us(), x.config()).unwrap_or_else(|_| {
+            warn!("Trigger delay {=u32} us does not fit at {=u32} mHz, output idle", x.delay_us, f); 0
+        }),
+    };
+    ACHIEVED_MHZ.store(achieved, Ordering::Relaxed);
+    if achieved != f { info!("Frequency requested {=u32} mHz, achieved {=u32} mHz", f, achieved); }
+}
+
+struct TrainRun { mode: TrainMode, on: bool, until: Option<Instant>, pulses: u32, mark: u32, cycles: u32 }
+
+impl TrainRun {
+    fn new(mode: TrainMode) -> Result<Self, TrainError> {
+        let ok = match mode {
+            TrainMode::Pulses(n) => n > 0,
+            TrainMode::Bursts { pulses, gap_ms, .. } => pulses > 0 && gap_ms > 0,
+            TrainMode::OnOff { on_s, off_s, .. } => on_s > 0 && off_s > 0,
+        };
+        if !ok { return Err(TrainError::BadProgram); }
+        Ok(Self { mode, on: false, until: None, pulses: 0, mark: 0, cycles: 0 })
     }
-    pub fn next(&mut self) -> u32 { self.idx = (self.idx+1)%self.table.len(); self.freq_hz = self.table[self.idx]; self.freq_hz }
-    pub fn set(&mut self, f: u32) -> u32 { self.freq_hz = f; f }
-    pub fn current(&self) -> u32 { self.freq_hz }
+    fn publish(&self) { TRAIN.lock(|t| t.set(Some(TrainStatus { mode: self.mode, on: self.on, pulses: self.pulses, mark: self.mark, cycles: self.cycles }))); }
+    /// Start the emitting phase: a counted train, or a timed free-running stretch.
+    fn start_on(&mut self, out: &mut PulseTimer, ctrl: &FrequencyControl) {
+        let (f, w) = (ctrl.current(), ctrl.width_us());
+        self.mark = pulses();
+        self.on = true;
+        let achieved = match self.mode {
+            TrainMode::Pulses(n) | TrainMode::Bursts { pulses: n, .. } => { self.until = None; out.start_train(f, w, n) }
+            TrainMode::OnOff { on_s, .. } => { self.until = Some(Instant::now() + Duration::from_secs(on_s as u64)); out.start(f, w) }
+        };
+        ACHIEVED_MHZ.store(achieved, Ordering::Relaxed);
+        self.publish();
+    }
+    /// End the emitting phase. Returns false once the program is finished, else starts the gap.
+    fn end_on(&mut self, out: &mut PulseTimer) -> bool {
+        out.stop();
+        self.pulses += pulses().wrapping_sub(self.mark);
+        self.on = false;
+        self.cycles += 1;
+        let (limit, gap_ms) = match self.mode {
+            TrainMode::Pulses(_) => (1, 0),
+            TrainMode::Bursts { gap_ms, bursts, .. } => (bursts, gap_ms as u64),
+            TrainMode::OnOff { off_s, cycles, .. } => (cycles, off_s as u64 * 1000),
+        };
+        self.until = Some(Instant::now() + Duration::from_millis(gap_ms));
+        self.publish();
+        limit == 0 || self.cycles < limit
+    }
+}
+
+/// `x^(1/n)` for x > 0 by Newton iteration; core has no float powers without libm.
+fn nth_root(x: f64, n: u32) -> f64 {
+    let pow = |y: f64, k: u32| (0..k).fold(1.0, |a, _| a * y);
+    let mut y = 1.0 + (x - 1.0) / n as f64;
+    for _ in 0..64 {
+        let next = y - (pow(y, n) - x) / (n as f64 * pow(y, n - 1));
+        if (next - y) * (next - y) < 1e-24 { return next; }
+        y = next;
+    }
+    y
+}
+
+struct SweepRun { cfg: Sweep, steps: u32, dwell: Duration, ratio: f64, step: u32, f: f64, passes: u32, next_at: Instant }
+
+impl SweepRun {
+    fn new(cfg: Sweep) -> Result<Self, SweepError> {
+        if [cfg.start_mhz, cfg.stop_mhz].iter().any(|&f| f == 0 || !in_range(f)) { return Err(SweepError::OutOfRange); }
+        let (steps, dwell_ms) = match cfg.length {
+            SweepLength::Steps { count, dwell_ms } => (count as u32, dwell_ms.unwrap_or(SWEEP_STEP_MS)),
+            SweepLength::DurationMs(ms) => { let n = (ms / SWEEP_STEP_MS).clamp(2, SWEEP_MAX_STEPS); (n, ms / n) }
+        };
+        if !(2..=SWEEP_MAX_STEPS).contains(&steps) || dwell_ms < SWEEP_MIN_DWELL_MS { return Err(SweepError::BadLength); }
+        let ratio = nth_root(cfg.stop_mhz as f64 / cfg.start_mhz as f64, steps - 1);
+        Ok(Self { cfg, steps, dwell: Duration::from_millis(dwell_ms as u64), ratio, step: 0, f: cfg.start_mhz as f64, passes: 0, next_at: Instant::now() })
+    }
+    /// Frequency of the current step; the last step lands exactly on `stop_mhz`.
+    fn freq(&self) -> u32 {
+        if self.step + 1 == self.steps { return self.cfg.stop_mhz; }
+        match self.cfg.scale {
+            SweepScale::Linear => {
+                let (a, b) = (self.cfg.start_mhz as i64, self.cfg.stop_mhz as i64);
+                (a + (b - a) * self.step as i64 / (self.steps as i64 - 1)) as u32
+            }
+            SweepScale::Log => (self.f + 0.5) as u32,
+        }
+    }
+    /// Move to the next step. Returns false when a single sweep has finished.
+    fn advance(&mut self) -> bool {
+        self.next_at += self.dwell;
+        if self.step + 1 < self.steps { self.step += 1; self.f *= self.ratio; return true; }
+        self.passes += 1;
+        if !self.cfg.repeat { return false; }
+        self.step = 0; self.f = self.cfg.start_mhz as f64;
+        true
+    }
+    fn publish(&self, f: u32) { SWEEP.lock(|s| s.set(Some(SweepProgress { sweep: self.cfg, step: self.step, steps: self.steps, freq_mhz: f, passes: self.passes }))); }
+}
+
+fn end_train(out: &mut PulseTimer, train: &mut Option<TrainRun>, why: &str) {
+    if let Some(t) = train.take() {
+        out.stop();
+        TRAIN.lock(|x| x.set(None));
+        info!("Pulse train {} {}: {=u32} pulses, {=u32} cycles", t.mode, why, t.pulses + if t.on { pulses().wrapping_sub(t.mark) } else { 0 }, t.cycles);
+    }
+}
+
+async fn load_presets() -> PresetTable {
+    let mut buf = [0u8; PresetTable::BYTES];
+    match storage::load(Region::Presets, &mut buf).await.ok().and_then(|_| PresetTable::from_bytes(&buf)) {
+        Some(t) => t,
+        None => { warn!("Frequency presets not found, using defaults"); PresetTable::default() }
+    }
+}
+
+/// Returns the frequency to switch to, if the command selected one.
+async fn preset_cmd(ctrl: &mut FrequencyControl, cmd: PresetCmd) -> Result<Option<u32>, PresetError> {
+    let t = ctrl.presets_mut();
+    match cmd {
+        PresetCmd::List => for (i, f) in t.freqs.iter().enumerate() { info!("preset {=usize} {=u32} mHz", i, f); },
+        PresetCmd::Add(f) => t.add(f)?,
+        PresetCmd::Set(i, f) => t.set(i as usize, f)?,
+        PresetCmd::Remove(i) => t.remove(i as usize)?,
+        PresetCmd::Clear => t.freqs.clear(),
+        PresetCmd::IncludeOff(on) => t.include_off = on,
+        PresetCmd::Wrap(on) => t.wrap = on,
+        PresetCmd::Defaults => *t = PresetTable::default(),
+        PresetCmd::Save => if storage::store(Region::Presets, &t.to_bytes()).await.is_err() { error!("Preset save failed"); },
+        PresetCmd::Recall(i) => return ctrl.recall(i as usize).map(Some),
+    }
+    info!("Presets {}", ctrl.presets());
+    Ok(None)
+}
+
+fn exit_capture(out: &mut PulseTimer, ctrl: &mut FrequencyControl, cap: CaptureMode, resume_mhz: u32) {
+    out.exit_capture();
+    CAPTURE_ACTIVE.store(false, Ordering::Relaxed);
+    MEASURED.lock(|m| m.set(None));
+    if cap.slave { ctrl.set(limited(resume_mhz).unwrap_or(0)); apply(out, ctrl); }
 }
 
 #[embassy_executor::task]
 pub async fn frequency_task<'d>(
     pa5: embassy_stm32::peripherals::PA5,
-    _tim2: TIM2,
+    pa2: embassy_stm32::peripherals::PA2,
+    pa1: embassy_stm32::peripherals::PA1,
+    tim2: TIM2,
     mut rx: Channel<FrequencyCmd, 8>::Receiver,
-    hv_tx: Channel<HvCommand, 8>::Sender,
 ) {
-    let mut ctrl = FrequencyControl::new();
-    let mut pin = Output::new(pa5, Level::Low, Speed::VeryHigh);
-    let mut running = true;
+    let mut ctrl = FrequencyControl::new(load_presets().await);
+    info!("Presets {}", ctrl.presets());
+    let mut out = PulseTimer::new(tim2, pa5, pa2, pa1);
+    let mut cap: Option<CaptureMode> = None;
+    let mut train: Option<TrainRun> = None;
+    let mut sweep: Option<SweepRun> = None;
+    let mut lb: Option<Loopback> = None;
+    apply(&mut out, &ctrl);
     loop {
-        while let Ok(cmd) = rx.try_receive() {
-            match cmd {
-                FrequencyCmd::CycleNext => {
-                    let f = ctrl.next();
-                    info!("Frequency -> {=u32} Hz", f);
-                    if f == 0 { running = false; let _ = hv_tx.send(HvCommand::ForceStop).await; pin.set_low(); } else { running = true; }
-                }
-                FrequencyCmd::EnterInputCaptureMode => { info!("Enter input capture mode PA2 (TIM2_CH3)"); }
-                FrequencyCmd::SetFrequency(f) => { ctrl.set(f); if f == 0 { running=false; pin.set_low(); } else { running = true; } }
-            }
-        }
-        if running {
-            let f = ctrl.current();
-            if f>0 { let half = 500_000u32 / f; pin.toggle(); Timer::after_micros(half as u64).await; }
-            else { pin.set_low(); Timer::after_millis(10).await; }
-        } else {
-            pin.set_low(); Timer::after_millis(50).await;
+        let poll = cap.as_ref().map(|_| CAPTURE_POLL_MS).or(lb.as_ref().map(|_| LOOPBACK_POLL_MS)).map(|ms| Instant::now() + Duration::from_millis(ms));
+        let wake = [poll, train.as_ref().and_then(|t| t.until), sweep.as_ref().map(|s| s.next_at)].into_iter().flatten().min().unwrap_or(Instant::MAX);
+        let (cmd, phase_over) = match select4(rx.receive(), Timer::at(wake), pulse_timer::TRAIN_DONE.wait(), pulse_timer::PERMIT_CHANGED.wait()).await {
+            Either4::First(c) => (Some(c), false),
+            Either4::Second(_) => (None, train.as_ref().and_then(|t| t.until).is_some_and(|u| Instant::now() >= u)),
+            Either4::Third(_) => (None, train.as_ref().is_some_and(|t| t.on)),
+            Either4::Fourth(permitted) => {
+                // The driver already forced PA5 low; finite programs do not survive the interruption
+                if !permitted {
+                    end_train(&mut out, &mut train, "aborted: HV left Running");
+                    if sweep.take().is_some() { SWEEP.lock(|x| x.set(None)); info!("Sweep aborted: HV left Running"); }
+                }
+                if train.is_none() { apply(&mut out, &ctrl); }
+                (None, false)
+            }
+        };
+        if phase_over {
+            if let Some(t) = train.as_mut() {
+                if safety::fault_latched() { end_train(&mut out, &mut train, "aborted by fault"); }
+                else if !t.on { t.start_on(&mut out, &ctrl); }
+                else if !t.end_on(&mut out) {
+                    // The output is stopped; the configured frequency stays for the next enable
+                    end_train(&mut out, &mut train, "complete");
+                    TRAIN_COMPLETE.signal(());
+                }
+            }
+        }
+        if sweep.as_ref().is_some_and(|s| Instant::now() >= s.next_at) {
+            let s = sweep.as_mut().unwrap();
+            match limited(s.freq()) {
+                Ok(f) => {
+                    ctrl.set(f);
+                    apply(&mut out, &ctrl);
+                    s.publish(f);
+                    debug!("Sweep step {=u32}/{=u32} -> {=u32} mHz", s.step + 1, s.steps, f);
+                    if !s.advance() { sweep = None; SWEEP.lock(|x| x.set(None)); info!("Sweep complete at {=u32} mHz", f); }
+                }
+                Err(e) => { sweep = None; SWEEP.lock(|x| x.set(None)); warn!("Sweep stopped at {=u32} mHz: {}", ctrl.current(), e); }
+            }
+        }
+        if cmd.is_some_and(|c| c.ends_train()) {
+            end_train(&mut out, &mut train, "aborted");
+            if sweep.take().is_some() { SWEEP.lock(|x| x.set(None)); info!("Sweep aborted"); }
+        }
+        match cmd {
+            None => {}
+            Some(FrequencyCmd::StartTrain(_) | FrequencyCmd::SetTrigger(Some(_)) | FrequencyCmd::SetCaptureSlave(true)) if sweep.is_some() => warn!("Refused: sweep running"),
+            Some(FrequencyCmd::StartTrain(mode)) => {
+                let t = if ctrl.current() == 0 { Err(TrainError::NoFrequency) }
+                    else if safety::fault_latched() { Err(TrainError::FaultLatched) }
+                    else if !pulse_timer::output_permitted() { Err(TrainError::HvNotRunning) }
+                    else if ctrl.ext.is_some() { Err(TrainError::Busy) }
+                    else { TrainRun::new(mode) };
+                match t {
+                    Ok(mut t) => {
+                        end_train(&mut out, &mut train, "replaced");
+                        if let Some(c) = cap.as_mut() { c.slave = false; }
+                        t.start_on(&mut out, &ctrl);
+                        info!("Pulse train {} at {=u32} mHz", mode, ctrl.current());
+                        train = Some(t);
+                    }
+                    Err(e) => warn!("Pulse train {} refused: {}", mode, e),
+                }
+            }
+            Some(FrequencyCmd::StartSweep(cfg)) => {
+                let r = if !pulse_timer::output_permitted() { Err(SweepError::HvNotRunning) }
+                    else if train.is_some() || ctrl.ext.is_some() || cap.as_ref().is_some_and(|c| c.slave) { Err(SweepError::Busy) }
+                    else { SweepRun::new(cfg) };
+                match r {
+                    Ok(s) => { info!("Sweep {} over {=u32} steps", cfg, s.steps); sweep = Some(s); }
+                    Err(e) => warn!("Sweep {} refused: {}", cfg, e),
+                }
+            }
+            Some(FrequencyCmd::SetMarkers(m)) => {
+                pulse_timer::set_markers(m);
+                // A running train picks the new markers up at its next burst
+                if train.is_none() { apply(&mut out, &ctrl); }
+                info!("Markers {}", m);
+            }
+            Some(FrequencyCmd::StopSweep) => {
+                if sweep.take().is_some() { SWEEP.lock(|x| x.set(None)); info!("Sweep stopped at {=u32} mHz", ctrl.current()); }
+            }
+            Some(FrequencyCmd::StopTrain) => { end_train(&mut out, &mut train, "stopped"); ctrl.set(0); apply(&mut out, &ctrl); }
+            Some(FrequencyCmd::SetPulseWidthUs(_) | FrequencyCmd::SetDutyPermille(_)) if train.is_some() => warn!("Pulse width change refused: pulse train running"),
+            Some(cmd @ (FrequencyCmd::CycleNext | FrequencyCmd::CyclePrev)) => {
+                if let Some(c) = cap.as_mut() { c.slave = false; }
+                // A refused step leaves both the frequency and the cycle position where they were
+                let (prev, prev_idx) = (ctrl.current(), ctrl.idx);
+                let f = if matches!(cmd, FrequencyCmd::CycleNext) { ctrl.next() } else { ctrl.prev() };
+                match limited(f) {
+                    Ok(f) => {
+                        ctrl.set(f);
+                        apply(&mut out, &ctrl);
+                        info!("Frequency -> {=u32} mHz", f);
+                        if f == 0 { safety::stop(StopReason::OutputStopped); }
+                    }
+                    Err(e) => { ctrl.idx = prev_idx; ctrl.set(prev); warn!("Preset {=u32} mHz refused: {}", f, e); }
+                }
+            }
+            Some(FrequencyCmd::Preset(p)) => {
+                let (prev, prev_idx) = (ctrl.current(), ctrl.idx);
+                match preset_cmd(&mut ctrl, p).await {
+                    Ok(Some(f)) => match limited(f) {
+                        Ok(f) => {
+                            if let Some(c) = cap.as_mut() { c.slave = false; }
+                            ctrl.set(f); apply(&mut out, &ctrl);
+                            info!("Frequency -> {=u32} mHz", f);
+                        }
+                        Err(e) => { ctrl.idx = prev_idx; ctrl.set(prev); warn!("Preset {=u32} mHz refused: {}", f, e); }
+                    },
+                    Ok(None) => {}
+                    Err(e) => warn!("Preset {} refused: {}", p, e),
+                }
+            }
+            Some(FrequencyCmd::SetTrigger(None)) => {
+                if ctrl.ext.take().is_some() { out.disarm_external(); apply(&mut out, &ctrl); info!("External trigger off"); }
+            }
+            Some(FrequencyCmd::SetTrigger(Some(x))) => {
+                let r = if ctrl.current() == 0 { Err(TriggerError::NoFrequency) }
+                    else if safety::fault_latched() { Err(TriggerError::FaultLatched) }
+                    else if cap.is_some() || train.is_some() || (lb.is_some() && x.source == TriggerSource::Pa2) { Err(TriggerError::Busy) }
+                    else if x.mode == TriggerMode::Pulses(0) { Err(TriggerError::BadProgram) }
+                    else { out.arm_external(ctrl.current(), ctrl.width_us(), x.config()).map_err(|_| TriggerError::BadDelay) };
+                match r {
+                    Ok(achieved) => { ctrl.ext = Some(x); ACHIEVED_MHZ.store(achieved, Ordering::Relaxed); info!("External trigger {}", x); }
+                    Err(e) => { warn!("External trigger {} refused: {}", x, e); apply(&mut out, &ctrl); }
+                }
+            }
+            Some(FrequencyCmd::SoftTrigger(active)) => match ctrl.ext {
+                Some(x) if x.source == TriggerSource::Software => out.external_edge(active),
+                _ => warn!("Software trigger ignored: not armed for software source"),
+            },
+            Some(FrequencyCmd::SetLoopback(true)) if cap.is_some() || ctrl.ext.is_some_and(|x| x.source == TriggerSource::Pa2) => warn!("Loopback refused: PA2 in use"),
+            Some(FrequencyCmd::SetLoopback(true)) => if lb.is_none() {
+                out.enter_capture();
+                lb = Some(Loopback::new(&out));
+                info!("Output loopback check on PA2");
+            },
+            Some(FrequencyCmd::SetLoopback(false)) => if lb.take().is_some() {
+                out.exit_capture();
+                LOOPBACK.lock(|l| l.set(None));
+                info!("Output loopback check off");
+            },
+            Some(FrequencyCmd::EnterInputCaptureMode) if lb.is_some() => warn!("Input capture refused: loopback check active"),
+            Some(FrequencyCmd::EnterInputCaptureMode) if ctrl.ext.is_some() => warn!("Input capture refused: external trigger mode active"),
+            Some(FrequencyCmd::EnterInputCaptureMode) => {
+                if cap.is_none() {
+                    out.enter_capture();
+                    cap = Some(CaptureMode::new(ctrl.current()));
+                    CAPTURE_ACTIVE.store(true, Ordering::Relaxed);
+                    info!("Enter input capture mode PA2 (TIM2_CH3)");
+                }
+            }
+            Some(FrequencyCmd::ExitInputCaptureMode) => {
+                if let Some(c) = cap.take() { let resume = c.resume_mhz; exit_capture(&mut out, &mut ctrl, c, resume); info!("Exit input capture mode"); }
+            }
+            Some(FrequencyCmd::SetCaptureSlave(on)) => match cap.as_mut() {
+                Some(c) => { c.slave = on; info!("Capture slave {=bool}", on); }
+                None => warn!("Capture slave refused: not in capture mode"),
+            },
+            // An explicit frequency overrides the slaved rate
+            Some(FrequencyCmd::SetFrequency(f)) if !in_range(f) => warn!("Frequency {=u32} mHz out of range", f),
+            Some(FrequencyCmd::SetFrequency(f)) => match limited(f) {
+                Ok(f) => { if let Some(c) = cap.as_mut() { c.slave = false; } ctrl.set(f); apply(&mut out, &ctrl); }
+                Err(e) => warn!("Frequency {=u32} mHz refused: {}", f, e),
+            },
+            Some(FrequencyCmd::SetPulseWidthUs(us)) => match ctrl.set_width(PulseWidth::Micros(us)) {
+                Ok(()) => { apply(&mut out, &ctrl); info!("Pulse width -> {=u32} us", us); }
+                Err(e) => warn!("Pulse width {=u32} us refused at {=u32} mHz: {}", us, ctrl.current(), e),
+            },
+            Some(FrequencyCmd::SetDutyPermille(d)) => match ctrl.set_width(PulseWidth::DutyPermille(d)) {
+                Ok(()) => { apply(&mut out, &ctrl); info!("Duty -> {=u32} permille ({=u32} us)", d, ctrl.width_us()); }
+                Err(e) => warn!("Duty {=u32} permille refused at {=u32} mHz: {}", d, ctrl.current(), e),
+            },
+        }
+        if let Some(l) = lb.as_mut() {
+            // Trains, sweeps and gating change the rate on purpose; only stuck edges count then
+            let steady = train.is_none() && sweep.is_none() && ctrl.ext.is_none();
+            if let Err(e) = l.check(&out, steady) {
+                error!("Output loopback fault: {}", e);
+                end_train(&mut out, &mut train, "aborted by fault");
+                sweep = None; SWEEP.lock(|x| x.set(None));
+                safety::stop(StopReason::OutputFault);
+                *l = Loopback::new(&out);
+            }
+        }
+        let Some(c) = cap.as_mut() else { continue };
+        let m = out.capture();
+        if m.count != c.seen { c.seen = m.count; c.last_seen = Instant::now(); c.push(m); }
+        if c.last_seen.elapsed() > c.timeout() {
+            warn!("Input capture signal lost, leaving capture mode");
+            if let Some(c) = cap.take() { exit_capture(&mut out, &mut ctrl, c, 0); }
+            continue;
+        }
+        let Some(meas) = c.filtered() else { continue };
+        if measured() != Some(meas) { MEASURED.lock(|x| x.set(Some(meas))); }
+        if c.slave && train.is_none() && meas.freq_mhz != ctrl.current() && in_range(meas.freq_mhz) {
+            // Out-of-envelope rates are not followed; the output keeps the last accepted one
+            if let Ok(f) = limited(meas.freq_mhz) { ctrl.set(f); apply(&mut out, &ctrl); }
         }
     }
 }
@@ -0,0 +1,259 @@
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::Vec;
+
+/* Gesture recognition on debounced button levels, time-stamped in milliseconds. Per button:
+   - click on release; with `double_ms` > 0 the click waits that long for a second press,
+     which turns it into a double-click instead;
+   - `Hold::Long` reports one long press, `Hold::Repeat` reports repeats that speed up and
+     grow their step while held;
+   - two buttons pressed within `chord_window_ms` form a chord, reported after both are held
+     for `chord_hold_ms`. Buttons in a chord never report their own clicks or holds. */
+
+pub const BUTTONS: usize = 3;
+
+#[derive(Clone, Copy, Debug, defmt::Format, PartialEq)]
+pub enum Button {
+    Pc,        // PB0
+    Polarity,  // PA9
+    Freq,      // PA12
+}
+
+impl Button {
+    pub const fn bit(self) -> u8 { 1 << self as u8 }
+    pub fn from_index(i: usize) -> Self { [Button::Pc, Button::Polarity, Button::Freq][i] }
+}
+
+/// PC and Polarity held together.
+pub const CHORD_PC_POL: u8 = Button::Pc.bit() | Button::Polarity.bit();
+/// PC and Freq held together.
+pub const CHORD_PC_FREQ: u8 = Button::Pc.bit() | Button::Freq.bit();
+
+#[derive(Clone, Copy, Debug, defmt::Format, PartialEq)]
+pub enum ButtonsEvent {
+    Click(Button),
+    DoubleClick(Button),
+    Long(Button),
+    /// Held on a `Hold::Repeat` button; `step` grows the longer it is held.
+    Repeat { button: Button, step: u8 },
+    /// Buttons (bit mask of `Button::bit`) pressed together and held.
+    Chord(u8),
+    /// Hold-to-run mode: the dead-man button was pressed.
+    DeadManHeld,
+}
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum Hold { Long, Repeat }
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub struct ButtonConfig { pub hold: Hold, pub long_ms: u32, pub double_ms: u32 }
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub struct GestureConfig {
+    pub buttons: [ButtonConfig; BUTTONS],
+    /// First repeat after `repeat_delay_ms`, then every `repeat_ms`, shrinking by a quarter per
+    /// repeat down to `repeat_min_ms`. The step doubles every `accel_every` repeats, up to 8.
+    pub repeat_delay_ms: u32,
+    pub repeat_ms: u32,
+    pub repeat_min_ms: u32,
+    pub accel_every: u32,
+    pub chord_window_ms: u32,
+    pub chord_hold_ms: u32,
+}
+
+pub const DEFAULT_CONFIG: GestureConfig = GestureConfig {
+    buttons: [
+        ButtonConfig { hold: Hold::Long, long_ms: 800, double_ms: 0 },   // PC
+        ButtonConfig { hold: Hold::Long, long_ms: 800, double_ms: 0 },   // Polarity: never delay or double a toggle
+        ButtonConfig { hold: Hold::Long, long_ms: 1000, double_ms: 250 }, // Freq
+    ],
+    repeat_delay_ms: 500,
+    repeat_ms: 200,
+    repeat_min_ms: 40,
+    accel_every: 5,
+    chord_window_ms: 150,
+    chord_hold_ms: 1000,
+};
+
+static CONFIG: Mutex<CriticalSectionRawMutex, Cell<GestureConfig>> = Mutex::new(Cell::new(DEFAULT_CONFIG));
+
+pub fn config() -> GestureConfig { CONFIG.lock(|c| c.get()) }
+/// Takes effect from the next press.
+pub fn set_config(cfg: GestureConfig) { CONFIG.lock(|c| c.set(cfg)); }
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Repeat { next: u64, interval: u64, count: u32 }
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Key {
+    down: bool,
+    since: u64,
+    /// A chord, long press or repeat has claimed this press; release reports nothing.
+    consumed: bool,
+    long_at: Option<u64>,
+    repeat: Option<Repeat>,
+    /// Released once; a click is reported at this time unless a second press comes first.
+    click_at: Option<u64>,
+    /// This press is the second of a possible double-click.
+    second: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Chord { mask: u8, fire_at: u64, fired: bool }
+
+/// At most a few events per button and one chord come due together.
+pub type Events = Vec<ButtonsEvent, 16>;
+
+pub struct GestureEngine { cfg: GestureConfig, keys: [Key; BUTTONS], chord: Option<Chord> }
+
+impl GestureEngine {
+    pub fn new(cfg: GestureConfig) -> Self { Self { cfg, keys: [Key::default(); BUTTONS], chord: None } }
+    pub fn set_config(&mut self, cfg: GestureConfig) { self.cfg = cfg; }
+
+    /// Debounced level change of `b` at `now_ms`.
+    pub fn input(&mut self, b: Button, down: bool, now_ms: u64, out: &mut Events) {
+        let i = b as usize;
+        let bc = self.cfg.buttons[i];
+        if down {
+            let others = self.keys.iter().enumerate().filter(|(j, k)| *j != i && k.down);
+            let (mask, first) = others.fold((0u8, now_ms), |(m, t), (j, k)| (m | 1 << j, t.min(k.since)));
+            let pending_click = self.keys[i].click_at.take();
+            let k = &mut self.keys[i];
+            *k = Key { down: true, since: now_ms, second: pending_click.is_some(), ..Key::default() };
+            if mask != 0 && now_ms.saturating_sub(first) <= self.cfg.chord_window_ms as u64 && self.chord.is_none() {
+                let mask = mask | 1 << i;
+                self.chord = Some(Chord { mask, fire_at: now_ms + self.cfg.chord_hold_ms as u64, fired: false });
+                for (j, k) in self.keys.iter_mut().enumerate() {
+                    if mask & 1 << j != 0 { k.consumed = true; k.long_at = None; k.repeat = None; k.click_at = None; }
+                }
+                return;
+            }
+            match bc.hold {
+                Hold::Long => k.long_at = Some(now_ms + bc.long_ms as u64),
+                Hold::Repeat => k.repeat = Some(Repeat { next: now_ms + self.cfg.repeat_delay_ms as u64, interval: self.cfg.repeat_ms as u64, count: 0 }),
+            }
+            return;
+        }
+        let k = &mut self.keys[i];
+        k.down = false; k.long_at = None; k.repeat = None;
+        if let Some(c) = self.chord {
+            if c.mask & 1 << i != 0 && !self.keys.iter().enumerate().any(|(j, k)| c.mask & 1 << j != 0 && k.down) { self.chord = None; }
+        }
+        let k = &mut self.keys[i];
+        if k.consumed { return; }
+        if k.second { k.second = false; push(out, ButtonsEvent::DoubleClick(b)); }
+        else if bc.double_ms == 0 { push(out, ButtonsEvent::Click(b)); }
+        else { k.click_at = Some(now_ms + bc.double_ms as u64); }
+    }
+
+    /// Report everything that has come due by `now_ms`.
+    pub fn poll(&mut self, now_ms: u64, out: &mut Events) {
+        for (i, k) in self.keys.iter_mut().enumerate() {
+            let b = Button::from_index(i);
+            if k.click_at.is_some_and(|t| now_ms >= t) { k.click_at = None; push(out, ButtonsEvent::Click(b)); }
+            if k.long_at.is_some_and(|t| now_ms >= t) {
+                // A first click followed by a long second press is a click and a long press
+                if k.second { k.second = false; push(out, ButtonsEvent::Click(b)); }
+                k.long_at = None; k.consumed = true;
+                push(out, ButtonsEvent::Long(b));
+            }
+            if let Some(r) = k.repeat.as_mut().filter(|r| now_ms >= r.next) {
+                if k.second { k.second = false; push(out, ButtonsEvent::Click(b)); }
+                k.consumed = true;
+                let step = 1u8 << (r.count / self.cfg.accel_every.max(1)).min(3);
+                push(out, ButtonsEvent::Repeat { button: b, step });
+                r.count += 1;
+                r.interval = (r.interval * 3 / 4).max(self.cfg.repeat_min_ms as u64);
+                r.next += r.interval;
+            }
+        }
+        if let Some(c) = self.chord.as_mut().filter(|c| !c.fired && now_ms >= c.fire_at) {
+            c.fired = true;
+            push(out, ButtonsEvent::Chord(c.mask));
+        }
+    }
+
+    /// Next time `poll` has something to decide.
+    pub fn deadline(&self) -> Option<u64> {
+        let keys = self.keys.iter().flat_map(|k| [k.click_at, k.long_at, k.repeat.map(|r| r.next)]).flatten();
+        keys.chain(self.chord.filter(|c| !c.fired).map(|c| c.fire_at)).min()
+    }
+}
+
+fn push(out: &mut Events, e: ButtonsEvent) { let _ = out.push(e); }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Button::*;
+    use ButtonsEvent::*;
+
+    /// Drive the engine like `buttons_task`: edges at their times, polls at every deadline.
+    fn run(cfg: GestureConfig, edges: &[(u64, Button, bool)], end: u64) -> std::vec::Vec<(u64, ButtonsEvent)> {
+        let mut e = GestureEngine::new(cfg);
+        let mut seen = std::vec::Vec::new();
+        let mut out = Events::new();
+        let mut edges = edges.iter().peekable();
+        loop {
+            let next_edge = edges.peek().map(|e| e.0);
+            let t = match (e.deadline(), next_edge) {
+                (Some(d), Some(x)) => d.min(x),
+                (d, x) => match d.or(x) { Some(t) => t, None => break },
+            };
+            if t > end { break; }
+            e.poll(t, &mut out);
+            if next_edge == Some(t) { let &(_, b, down) = edges.next().unwrap(); e.input(b, down, t, &mut out); }
+            seen.extend(out.iter().map(|&ev| (t, ev)));
+            out.clear();
+        }
+        seen
+    }
+
+    #[test]
+    fn click_is_immediate_without_double_click() {
+        assert_eq!(run(DEFAULT_CONFIG, &[(10, Pc, true), (100, Pc, false)], 5000), [(100, Click(Pc))]);
+    }
+
+    #[test]
+    fn click_waits_for_a_possible_double_click() {
+        assert_eq!(run(DEFAULT_CONFIG, &[(10, Freq, true), (100, Freq, false)], 5000), [(350, Click(Freq))]);
+        let ev = run(DEFAULT_CONFIG, &[(10, Freq, true), (100, Freq, false), (200, Freq, true), (300, Freq, false)], 5000);
+        assert_eq!(ev, [(300, DoubleClick(Freq))]);
+    }
+
+    #[test]
+    fn long_press_at_the_threshold_and_nothing_on_release() {
+        assert_eq!(run(DEFAULT_CONFIG, &[(0, Pc, true), (799, Pc, false)], 5000), [(799, Click(Pc))]);
+        assert_eq!(run(DEFAULT_CONFIG, &[(0, Pc, true), (2000, Pc, false)], 5000), [(800, Long(Pc))]);
+    }
+
+    #[test]
+    fn chord_fires_after_the_hold_and_suppresses_clicks() {
+        let ev = run(DEFAULT_CONFIG, &[(0, Pc, true), (50, Polarity, true), (1500, Pc, false), (1510, Polarity, false)], 5000);
+        assert_eq!(ev, [(1050, Chord(CHORD_PC_POL))]);
+        let ev = run(DEFAULT_CONFIG, &[(0, Pc, true), (50, Polarity, true), (500, Pc, false), (510, Polarity, false)], 5000);
+        assert!(ev.is_empty());
+    }
+
+    #[test]
+    fn presses_outside_the_chord_window_stay_separate() {
+        let ev = run(DEFAULT_CONFIG, &[(0, Pc, true), (300, Polarity, true), (400, Polarity, false), (500, Pc, false)], 5000);
+        assert_eq!(ev, [(400, Click(Polarity)), (500, Click(Pc))]);
+    }
+
+    #[test]
+    fn repeat_accelerates_to_the_floor_and_grows_its_step() {
+        let mut cfg = DEFAULT_CONFIG;
+        cfg.buttons[0].hold = Hold::Repeat;
+        let ev = run(cfg, &[(0, Pc, true), (3000, Pc, false)], 5000);
+        assert_eq!(ev[0], (500, ButtonsEvent::Repeat { button: Pc, step: 1 }));
+        let gaps: std::vec::Vec<u64> = ev.windows(2).map(|w| w[1].0 - w[0].0).collect();
+        assert!(gaps.windows(2).all(|g| g[1] <= g[0]));
+        assert_eq!(*gaps.last().unwrap(), 40);
+        let steps: std::vec::Vec<u8> = ev.iter().map(|e| match e.1 { ButtonsEvent::Repeat { step, .. } => step, _ => panic!() }).collect();
+        assert_eq!(&steps[..11], [1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 4]);
+        assert_eq!(*steps.last().unwrap(), 8);
+        // A release after repeats reports nothing more
+        assert!(ev.iter().all(|e| e.0 < 3000));
+    }
+}
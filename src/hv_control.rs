@@ -1,53 +1,446 @@
+use core::cell::Cell;
 use defmt::*;
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Timer};
+use core::future::Future;
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_sync::channel::mpmc::Channel;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use crate::drivers::mcp23017::Mcp23017;
-use crate::dac_control::DacCmd;
-use crate::frequency_control::FrequencyCmd;
-
-const GPB0: u8 = 1 << 0;
-const GPB1: u8 = 1 << 1;
-const GPB2: u8 = 1 << 2;
-const GPB3: u8 = 1 << 3;
-const GPB4: u8 = 1 << 4; // +Step_vtg relay
-const GPB5: u8 = 1 << 5; // CTGP_RELAY
-const GPB6: u8 = 1 << 6; // HV_ON
-const GPB7: u8 = 1 << 7; // Cin_RLY1
-const GPA6: u8 = 1 << 6; // -Step_vtg relay
-const GPA7: u8 = 1 << 7; // Cin_RLY2
+use crate::drivers::pulse_timer::{self, PulseTimer};
+use crate::dac_control::{self, DacCmd};
+use crate::deadman;
+use crate::envelope::{self, Violation};
+use crate::frequency_control::{self, FrequencyCmd};
+use crate::relays::{OutputImage, PolarityRelays, RelayCounters, RelayError};
+use crate::storage::{self, Region};
+use crate::safety::{self, StopReason};
+
+const ARM_TIMEOUT_MS: u64 = 5_000; // Enable must follow Arm within this window
+const HV_ON_SETTLE_MS: u64 = 10;
+const BOOT_POLARITY: Polarity = Polarity::Positive;
+const STEP_MIN_MS: u32 = 5; // step relay operate/release time
+const ALT_MIN_INTERVAL_MS: u32 = 3_000; // must exceed the 2150 ms discharge hold plus relay settling
+const ALT_POLL_MS: u64 = 100; // pulse-count re-check while the output is stopped
+const COUNTER_SAVE_MIN_S: u64 = 60; // never save more often than this
+const MARKER_POLARITY_US: u64 = 200;
+const MARKER_HV_ENABLE_US: u64 = 500;
 
 #[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
 pub enum Polarity { Positive, Negative }
 
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum HvState { Off, Armed, Enabling, Discharging, WaitingForDischarge, PreSetting, Completing, Toggling, Restoring, Running, Stepping }
+
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub enum HvCommand { RequestPolarityToggle, SetPolarity(Polarity), SelectCin(CinRange), StartStep(StepProgram), StopStep, StartAlternating(AltTrigger), StopAlternating(Option<Polarity>), ResetFaults, Arm, Enable, Disable }
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum StepDir { Positive, Negative }
+
+/// Step relay engagement: hold for `on_ms`, release for `off_ms`, `count` times (0 = until `StopStep`).
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct StepProgram { pub dir: StepDir, pub on_ms: u32, pub off_ms: u32, pub count: u16 }
+
+/// When the automatic alternating mode reverses polarity.
 #[derive(Copy, Clone, Debug, defmt::Format)]
-pub enum HvState { Off, Discharging, WaitingForDischarge, PreSetting, Completing, Toggling, Restoring, Running }
+pub enum AltTrigger { IntervalMs(u32), Pulses(u32) }
+
+struct AltRun { trigger: AltTrigger, next_at: Instant, pulse_mark: u32 }
+
+struct StepRun { prog: StepProgram, engaged: bool, done: u32, next_edge: Instant }
+
+/// Input capacitance configuration; each Cin relay switches its bank in parallel.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum CinRange { Base, Bank1, Bank2, Both }
+
+impl CinRange {
+    pub fn next(self) -> Self {
+        match self { CinRange::Base => CinRange::Bank1, CinRange::Bank1 => CinRange::Bank2, CinRange::Bank2 => CinRange::Both, CinRange::Both => CinRange::Base }
+    }
+    fn apply_to(self, img: &mut OutputImage) {
+        img.set_cin1(matches!(self, CinRange::Bank1 | CinRange::Both));
+        img.set_cin2(matches!(self, CinRange::Bank2 | CinRange::Both));
+    }
+}
+
+/// Output stage pulse limits: the switch needs a minimum on-time and recovery for the rest of the period.
+pub const MIN_PULSE_WIDTH_US: u32 = 10;
+pub const MAX_DUTY_PERMILLE: u32 = 500;
+
+/// Why an `Arm`/`Enable` request was refused.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum EnableError { FaultLatched, NotDischarged, PolarityInvalid, NoFrequency, OutsideEnvelope(Violation), NotArmed, Busy, Io, DeadManNotHeld }
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum AltError { Busy, BadInterval }
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum StepError { HvOn, Busy, FaultLatched, PolarityInvalid, BadTiming, Relay(RelayError) }
 
+/// Snapshot of the HV stage published by `hv_task` after every command.
 #[derive(Copy, Clone, Debug, defmt::Format)]
-pub enum HvCommand { RequestPolarityToggle, ForceStop }
+pub struct HvStatus {
+    pub state: HvState,
+    /// Polarity held in the expander output latch; `None` until confirmed. There is no contact
+    /// feedback, so this shows the expander took the write, not that the relays moved.
+    pub pol: Option<Polarity>,
+    pub cin: CinRange,
+    pub alternating: bool,
+    /// Reversals completed by the alternating mode since it was last started.
+    pub reversals: u32,
+    /// False when the relay expander failed to initialise at boot; every relay change and HV enable is refused.
+    pub expander_ok: bool,
+}
+
+pub static HV_STATUS: Mutex<CriticalSectionRawMutex, Cell<HvStatus>> =
+    Mutex::new(Cell::new(HvStatus { state: HvState::Off, pol: None, cin: CinRange::Base, alternating: false, reversals: 0, expander_ok: false }));
+
+pub fn status() -> HvStatus { HV_STATUS.lock(|s| s.get()) }
+
+pub static RELAY_COUNTS: Mutex<CriticalSectionRawMutex, Cell<RelayCounters>> = Mutex::new(Cell::new(RelayCounters { ops: [0; crate::relays::RELAY_COUNT] }));
+
+pub fn relay_counters() -> RelayCounters { RELAY_COUNTS.lock(|c| c.get()) }
 
 pub struct HvController<'d> {
     exp: Mcp23017<'d>,
+    expander_ok: bool,
     state: HvState,
     pol: Polarity,
-    gpa: u8,
-    gpb: u8,
+    cin: CinRange,
+    out: OutputImage,
+    armed_at: Option<Instant>,
+    step: Option<StepRun>,
+    alt: Option<AltRun>,
+    reversals: u32,
+    counters: RelayCounters,
+    counters_dirty: bool,
+    counters_saved_at: Instant,
 }
 
 impl<'d> HvController<'d> {
-    pub fn new(exp: Mcp23017<'d>) -> Self { Self { exp, state: HvState::Off, pol: Polarity::Positive, gpa: 0, gpb: 0 } }
-    async fn apply(&mut self) -> Result<(), ()> { self.exp.write_gpa(self.gpa).await?; self.exp.write_gpb(self.gpb).await }
-    async fn set_polarity(&mut self, pol: Polarity) -> Result<(), ()> {
-        self.gpb &= !(GPB0|GPB1|GPB2|GPB3);
-        match pol {
-            Polarity::Positive => { self.gpb |= GPB0 | GPB3; }
-            Polarity::Negative => { self.gpb |= GPB1 | GPB2; }
+    pub fn new(exp: Mcp23017<'d>) -> Self { Self { exp, expander_ok: false, state: HvState::Off, pol: Polarity::Positive, cin: CinRange::Base, out: OutputImage::default(), armed_at: None, step: None, alt: None, reversals: 0, counters: RelayCounters::default(), counters_dirty: false, counters_saved_at: Instant::now() } }
+    /// Every state change goes through here: the stimulus output is only released in `Running`.
+    fn set_state(&mut self, s: HvState) {
+        self.state = s;
+        pulse_timer::set_inhibit(s != HvState::Running);
+    }
+    /// Validate `next` against the current image, then write it; the image only advances on success.
+    async fn apply(&mut self, next: OutputImage) -> Result<(), RelayError> {
+        if !self.expander_ok { return Err(RelayError::Io); }
+        if let Err(e) = self.out.validate(&next) { error!("Relay image {} rejected: {}", next, e); return Err(e); }
+        self.exp.write_gpa(next.gpa()).await.map_err(|_| RelayError::Io)?;
+        self.exp.write_gpb(next.gpb()).await.map_err(|_| RelayError::Io)?;
+        let prev = core::mem::replace(&mut self.out, next);
+        if self.counters.record(&prev, &next, |r, n| warn!("Relay {} at {=u32} operations, nearing end of life", r, n)) {
+            self.counters_dirty = true;
+        }
+        Ok(())
+    }
+    async fn load_counters(&mut self) {
+        let mut buf = [0u8; RelayCounters::BYTES];
+        match storage::load(Region::RelayCounters, &mut buf).await {
+            Ok(()) => self.counters = RelayCounters::from_bytes(&buf),
+            Err(_) => warn!("Relay counters not found, starting from zero"),
+        }
+        for (r, n) in self.counters.near_end_of_life() { warn!("Relay {} at {=u32} operations, nearing end of life", r, n); }
+    }
+    /// Flash writes stall the CPU, TIM2 and the dead-man poll included, so counters are only
+    /// saved with HV off; counts made while on wait for the next disable.
+    fn counters_save_due(&self) -> Option<Instant> {
+        if !self.counters_dirty || self.state != HvState::Off { return None; }
+        Some(self.counters_saved_at + Duration::from_secs(COUNTER_SAVE_MIN_S))
+    }
+    async fn save_counters(&mut self) {
+        if storage::store(Region::RelayCounters, &self.counters.to_bytes()).await.is_err() { error!("Relay counter save failed"); }
+        self.counters_dirty = false;
+        self.counters_saved_at = Instant::now();
+    }
+    async fn set_polarity(&mut self, pol: Polarity) -> Result<(), RelayError> {
+        let mut next = self.out;
+        next.set_pol_pos_a(pol == Polarity::Positive); next.set_pol_pos_b(pol == Polarity::Positive);
+        next.set_pol_neg_a(pol == Polarity::Negative); next.set_pol_neg_b(pol == Polarity::Negative);
+        self.apply(next).await?; self.pol = pol; Ok(())
+    }
+    async fn set_cin(&mut self, cin: CinRange) -> Result<(), RelayError> {
+        let mut next = self.out;
+        cin.apply_to(&mut next);
+        self.apply(next).await?; self.cin = cin; Ok(())
+    }
+    /// Compare the expander output latches with the image and report the polarity they hold.
+    /// OLATA/OLATB only show what was written, not the relay contacts.
+    async fn confirm_polarity(&mut self) -> Result<Polarity, ()> {
+        if !self.expander_ok { return Err(()); }
+        let olata = self.exp.read_olata().await?;
+        let olatb = self.exp.read_olatb().await?;
+        if olata != self.out.gpa() || olatb != self.out.gpb() { return Err(()); }
+        if self.polarity_relays_valid() { Ok(self.pol) } else { Err(()) }
+    }
+    /// Drive every relay to a known state at boot instead of trusting the power-on latch.
+    async fn restore_at_boot(&mut self) -> Result<Polarity, ()> {
+        self.exp.write_gpa(0).await?; self.exp.write_gpb(0).await?;
+        self.out = OutputImage::default(); self.cin = CinRange::Base;
+        self.set_polarity(BOOT_POLARITY).await.map_err(|_| ())?;
+        self.confirm_polarity().await
+    }
+    async fn publish(&mut self) {
+        let pol = self.confirm_polarity().await.ok();
+        let st = HvStatus { state: self.state, pol, cin: self.cin, alternating: self.alt.is_some(), reversals: self.reversals, expander_ok: self.expander_ok };
+        HV_STATUS.lock(|s| s.set(st));
+        RELAY_COUNTS.lock(|c| c.set(self.counters));
+    }
+    async fn set_hv_on(&mut self, on: bool) -> Result<(), RelayError> { let mut next = self.out; next.set_hv_on(on); self.apply(next).await }
+    fn polarity_relays_valid(&self) -> bool {
+        match (self.out.polarity(), self.pol) {
+            (Ok(PolarityRelays::Positive), Polarity::Positive) => true,
+            (Ok(PolarityRelays::Negative), Polarity::Negative) => true,
+            _ => false,
+        }
+    }
+    fn check_preconditions(&self, f: u32) -> Result<(), EnableError> {
+        if !self.expander_ok { return Err(EnableError::Io); }
+        if safety::fault_latched() { return Err(EnableError::FaultLatched); }
+        if !deadman::enable_permitted() { return Err(EnableError::DeadManNotHeld); }
+        if !safety::discharged() { return Err(EnableError::NotDischarged); }
+        if !self.polarity_relays_valid() { return Err(EnableError::PolarityInvalid); }
+        if f == 0 { return Err(EnableError::NoFrequency); }
+        // The envelope may have been tightened since the setpoints were accepted
+        let (max_f, max_v) = envelope::with(|e| (e.cin_max_mhz(self.cin), e.max_volts(f, self.cin)));
+        if f > max_f { return Err(EnableError::OutsideEnvelope(Violation::FrequencyTooHigh { max_mhz: max_f, volts: dac_control::setpoint_v() })); }
+        if dac_control::setpoint_v() > max_v { return Err(EnableError::OutsideEnvelope(Violation::VoltageTooHigh { max_v, freq_mhz: f })); }
+        Ok(())
+    }
+    fn arm(&mut self, f: u32) -> Result<(), EnableError> {
+        if self.state != HvState::Off { return Err(EnableError::Busy); }
+        self.check_preconditions(f)?;
+        self.armed_at = Some(Instant::now());
+        self.set_state(HvState::Armed);
+        Ok(())
+    }
+    fn arm_deadline(&self) -> Option<Instant> { self.armed_at.map(|t| t + Duration::from_millis(ARM_TIMEOUT_MS)) }
+    /// On-sequence: re-check preconditions, assert HV_ON, wait for the supply to settle.
+    async fn enable(&mut self, f: u32) -> Result<(), EnableError> {
+        if self.state != HvState::Armed { return Err(EnableError::NotArmed); }
+        self.armed_at = None;
+        if let Err(e) = self.check_preconditions(f) { self.set_state(HvState::Off); return Err(e); }
+        self.set_state(HvState::Enabling);
+        if self.set_hv_on(true).await.is_err() { let _ = self.set_hv_on(false).await; self.set_state(HvState::Off); return Err(EnableError::Io); }
+        marker(pulse_timer::markers().hv_enable, MARKER_HV_ENABLE_US).await;
+        Timer::after_millis(HV_ON_SETTLE_MS).await;
+        self.set_state(HvState::Running);
+        Ok(())
+    }
+    async fn disable(&mut self) -> Result<(), RelayError> {
+        self.armed_at = None; self.step = None; self.set_state(HvState::Off);
+        let mut next = self.out;
+        next.set_hv_on(false); next.set_step_pos(false); next.set_step_neg(false);
+        self.apply(next).await
+    }
+    /// Drive at most one step relay; the image validation refuses it while HV_ON is asserted.
+    async fn set_step_relay(&mut self, dir: Option<StepDir>) -> Result<(), StepError> {
+        let mut next = self.out;
+        next.set_step_pos(dir == Some(StepDir::Positive));
+        next.set_step_neg(dir == Some(StepDir::Negative));
+        self.apply(next).await.map_err(StepError::Relay)
+    }
+    async fn start_step(&mut self, prog: StepProgram) -> Result<(), StepError> {
+        if self.state != HvState::Off { return Err(if self.state == HvState::Running { StepError::HvOn } else { StepError::Busy }); }
+        if safety::fault_latched() { return Err(StepError::FaultLatched); }
+        if !self.polarity_relays_valid() { return Err(StepError::PolarityInvalid); }
+        if prog.on_ms < STEP_MIN_MS || prog.off_ms < STEP_MIN_MS { return Err(StepError::BadTiming); }
+        self.set_step_relay(Some(prog.dir)).await?;
+        self.set_state(HvState::Stepping);
+        self.step = Some(StepRun { prog, engaged: true, done: 0, next_edge: Instant::now() + Duration::from_millis(prog.on_ms as u64) });
+        Ok(())
+    }
+    async fn stop_step(&mut self) {
+        self.step = None;
+        let _ = self.set_step_relay(None).await;
+        if self.state == HvState::Stepping { self.set_state(HvState::Off); }
+    }
+    async fn step_edge(&mut self) {
+        let Some(run) = self.step.as_mut() else { return };
+        let now = Instant::now();
+        if run.engaged {
+            run.engaged = false; run.done = run.done.saturating_add(1);
+            run.next_edge = now + Duration::from_millis(run.prog.off_ms as u64);
+            let finished = run.prog.count != 0 && run.done >= run.prog.count as u32;
+            if finished { info!("HV step program complete"); self.stop_step().await; return; }
+            if self.set_step_relay(None).await.is_err() { self.stop_step().await; }
+        } else {
+            run.engaged = true;
+            run.next_edge = now + Duration::from_millis(run.prog.on_ms as u64);
+            let dir = run.prog.dir;
+            if self.set_step_relay(Some(dir)).await.is_err() { self.stop_step().await; }
+        }
+    }
+    fn start_alternating(&mut self, trigger: AltTrigger) -> Result<(), AltError> {
+        if self.step.is_some() || self.alt.is_some() { return Err(AltError::Busy); }
+        // Zero pulses would make every check due and reverse as fast as the relays allow
+        match trigger {
+            AltTrigger::IntervalMs(ms) if ms < ALT_MIN_INTERVAL_MS => return Err(AltError::BadInterval),
+            AltTrigger::Pulses(0) => return Err(AltError::BadInterval),
+            _ => {}
         }
-        let b0 = (self.gpb & GPB0)!=0; let b1=(self.gpb & GPB1)!=0; let b2=(self.gpb & GPB2)!=0; let b3=(self.gpb & GPB3)!=0;
-        assert!((b0 as u8) ^ (b1 as u8) == 1);
-        assert!((b2 as u8) ^ (b3 as u8) == 1);
-        self.apply().await?; self.pol = pol; Ok(())
+        self.reversals = 0;
+        self.alt = Some(AltRun { trigger, next_at: Instant::now(), pulse_mark: frequency_control::pulses() });
+        self.schedule_reversal();
+        Ok(())
+    }
+    /// Set the next check point: the interval from now, or the time the remaining pulses should take.
+    fn schedule_reversal(&mut self) {
+        let Some(alt) = self.alt.as_mut() else { return };
+        let now = Instant::now();
+        alt.next_at = match alt.trigger {
+            AltTrigger::IntervalMs(ms) => now + Duration::from_millis(ms as u64),
+            AltTrigger::Pulses(n) => {
+                let left = n.saturating_sub(frequency_control::pulses().wrapping_sub(alt.pulse_mark));
+                match frequency_control::current_mhz() {
+                    0 => now + Duration::from_millis(ALT_POLL_MS),
+                    f => now + Duration::from_millis((left as u64 * 1_000_000 / f as u64).max(1)),
+                }
+            }
+        };
+    }
+    /// True when the alternating mode is due to reverse; otherwise reschedules the next check.
+    fn reversal_due(&mut self) -> bool {
+        let Some(alt) = self.alt.as_ref() else { return false };
+        if Instant::now() < alt.next_at { return false; }
+        let due = match alt.trigger {
+            AltTrigger::IntervalMs(_) => true,
+            AltTrigger::Pulses(n) => frequency_control::pulses().wrapping_sub(alt.pulse_mark) >= n,
+        };
+        if !due { self.schedule_reversal(); }
+        due
+    }
+    fn reversal_done(&mut self) {
+        self.reversals += 1;
+        if let Some(alt) = self.alt.as_mut() { alt.pulse_mark = frequency_control::pulses(); }
+        self.schedule_reversal();
+    }
+    fn next_deadline(&self) -> Option<Instant> {
+        [self.arm_deadline(), self.step.as_ref().map(|r| r.next_edge), self.alt.as_ref().map(|a| a.next_at), self.counters_save_due(), self.deadman_check()].into_iter().flatten().min()
+    }
+    async fn on_deadline(&mut self) {
+        let now = Instant::now();
+        if self.arm_deadline().is_some_and(|d| now >= d) { warn!("HV arm timeout"); self.armed_at = None; self.set_state(HvState::Off); }
+        if self.step.as_ref().is_some_and(|r| now >= r.next_edge) { self.step_edge().await; }
+        if self.counters_save_due().is_some_and(|d| now >= d) { self.save_counters().await; }
+        if self.deadman_check().is_some() && (deadman::stale(now.as_millis()) || (self.state == HvState::Running && !deadman::held())) {
+            error!("Dead-man hold not confirmed by the button watcher");
+            safety::stop(StopReason::DeadMan);
+        }
+    }
+    /// Poll the dead-man watchdog while HV is on in hold-to-run mode.
+    fn deadman_check(&self) -> Option<Instant> {
+        (deadman::mode().is_some() && self.state != HvState::Off).then(|| Instant::now() + Duration::from_millis(deadman::WATCHDOG_MS as u64 / 2))
+    }
+}
+
+/// Software-timed PA1 marker for relay events. It rises as soon as the expander write has
+/// returned (the I2C transfer, ~0.5 ms at 100 kHz, after the command); width is rounded up
+/// to the embassy tick (~31 us).
+async fn marker(enabled: bool, us: u64) {
+    if !enabled { return; }
+    PulseTimer::marker_force(true);
+    Timer::after_micros(us).await;
+    PulseTimer::marker_force(false);
+}
+
+/// What `resume` needs to bring the stage back as it was before `discharge`.
+#[derive(Copy, Clone)]
+struct Resume { was_running: bool, f: u32, volts: f32 }
+
+/// Take the stage down and wait out the mandatory hold before any relay is switched.
+async fn discharge<'d>(
+    hv: &mut HvController<'d>,
+    dac_tx: &Channel<DacCmd, 8>::Sender,
+    freq_tx: &Channel<FrequencyCmd, 8>::Sender,
+) -> Resume {
+    let r = Resume { was_running: hv.state == HvState::Running, f: frequency_control::current_mhz(), volts: dac_control::setpoint_v() };
+    // Leaving Running forces PA5 low before anything else moves
+    hv.set_state(HvState::Discharging);
+    let _ = dac_tx.send(DacCmd::SetHvVolts(0.0)).await;
+    // The frequency is kept: the PA5 interlock holds the output low until Running again
+    let _ = hv.disable().await;
+    hv.set_state(HvState::WaitingForDischarge);
+    Timer::after_millis(2150).await; // mandatory hold
+    r
+}
+
+/// Resume through the normal on-sequence so the preconditions are re-checked, then bring the
+/// setpoint back; dac_task checks it against the envelope for the new polarity or Cin.
+async fn resume<'d>(
+    hv: &mut HvController<'d>,
+    r: Resume,
+    dac_tx: &Channel<DacCmd, 8>::Sender,
+    freq_tx: &Channel<FrequencyCmd, 8>::Sender,
+) {
+    hv.set_state(HvState::Off);
+    let f = r.f.min(envelope::with(|e| e.cin_max_mhz(hv.cin)));
+    if f != r.f { let _ = freq_tx.send(FrequencyCmd::SetFrequency(f)).await; }
+    if !r.was_running { return; }
+    hv.set_state(HvState::Armed);
+    match hv.enable(f).await {
+        Ok(()) => { let _ = dac_tx.send(DacCmd::SetHvVolts(r.volts)).await; }
+        Err(e) => warn!("HV resume refused: {}", e),
     }
-    async fn hv_on_update(&mut self, f: u32) -> Result<(), ()> { if f>0 { self.gpb |= GPB6; } else { self.gpb &= !GPB6; } self.exp.write_gpb(self.gpb).await }
+}
+
+/// Discharge, pre-set the new polarity relays, pulse the CTGP/+Step pair, then resume if HV was running.
+async fn polarity_sequence<'d>(
+    hv: &mut HvController<'d>,
+    target: Polarity,
+    dac_tx: &Channel<DacCmd, 8>::Sender,
+    freq_tx: &Channel<FrequencyCmd, 8>::Sender,
+) {
+    info!("HV polarity change to {} start", target);
+    let r = discharge(hv, dac_tx, freq_tx).await;
+    hv.set_state(HvState::PreSetting);
+    if hv.set_polarity(target).await.is_ok() { marker(pulse_timer::markers().polarity, MARKER_POLARITY_US).await; }
+    Timer::after_millis(1).await; hv.set_state(HvState::Completing);
+    let mut pulse = hv.out; pulse.set_step_pos(true); pulse.set_ctgp(true);
+    let _ = hv.apply(pulse).await; Timer::after_millis(1).await; hv.set_state(HvState::Toggling);
+    pulse.set_step_pos(false); pulse.set_ctgp(false);
+    let _ = hv.apply(pulse).await; Timer::after_millis(1).await; hv.set_state(HvState::Restoring);
+    Timer::after_millis(100).await;
+    resume(hv, r, dac_tx, freq_tx).await;
+    info!("HV polarity switch complete");
+}
+
+/// Same discharge-before-switch discipline as the polarity sequence, applied to the Cin relays.
+async fn cin_sequence<'d>(
+    hv: &mut HvController<'d>,
+    target: CinRange,
+    dac_tx: &Channel<DacCmd, 8>::Sender,
+    freq_tx: &Channel<FrequencyCmd, 8>::Sender,
+) {
+    info!("HV Cin change to {} start", target);
+    let r = discharge(hv, dac_tx, freq_tx).await;
+    hv.set_state(HvState::PreSetting);
+    if hv.set_cin(target).await.is_err() { error!("HV Cin relay write failed"); }
+    hv.set_state(HvState::Restoring);
+    Timer::after_millis(100).await;
+    resume(hv, r, dac_tx, freq_tx).await;
+    info!("HV Cin now {}", hv.cin);
+}
+
+/// Run a relay sequence, dropping it at whichever await point it is parked on if a stop is signalled.
+async fn abortable<F: Future<Output = ()>>(seq: F) -> Option<StopReason> {
+    match select(seq, safety::STOP_SIGNAL.wait()).await { Either::First(()) => None, Either::Second(r) => Some(r) }
+}
+
+/// Stop path: HV_ON and step relays off and the output stopped without waiting on full queues.
+/// The DAC and its setpoint were already zeroed by `safety::stop`.
+async fn stop_path<'d>(
+    hv: &mut HvController<'d>,
+    reason: StopReason,
+    freq_tx: &Channel<FrequencyCmd, 8>::Sender,
+) {
+    if reason.is_emergency() { error!("HV emergency stop: {}", reason); } else { info!("HV stop: {}", reason); }
+    hv.alt = None;
+    if hv.disable().await.is_err() { error!("HV_ON release failed, KILL_N is the only barrier"); }
+    let _ = freq_tx.try_send(FrequencyCmd::SetFrequency(0));
 }
 
 #[embassy_executor::task]
@@ -58,35 +451,114 @@ pub async fn hv_task<'d>(
     mut rx: Channel<HvCommand, 8>::Receiver,
 ) {
     let mut hv = HvController::new(expander);
-    let mut freq: u32 = 0;
+    hv.load_counters().await;
+    // Without a configured expander the relay outputs are undefined: stay off and say so in the status
+    hv.expander_ok = hv.exp.init().await.is_ok();
+    if !hv.expander_ok { error!("Relay expander init failed, HV stays disabled"); }
+    else {
+        match hv.restore_at_boot().await {
+            Ok(pol) => info!("HV relays restored, polarity {}", pol),
+            Err(_) => error!("HV relay restore failed"),
+        }
+    }
+    hv.publish().await;
     loop {
-        let cmd = rx.receive().await;
+        let deadline = hv.next_deadline().unwrap_or(Instant::MAX);
+        let cmd = match select4(rx.receive(), Timer::at(deadline), safety::STOP_SIGNAL.wait(), frequency_control::TRAIN_COMPLETE.wait()).await {
+            Either4::First(cmd) => cmd,
+            Either4::Second(_) => {
+                hv.on_deadline().await;
+                if hv.reversal_due() {
+                    let new_pol = if hv.pol==Polarity::Positive { Polarity::Negative } else { Polarity::Positive };
+                    match abortable(polarity_sequence(&mut hv, new_pol, &dac_tx, &freq_tx)).await {
+                        None => { hv.reversal_done(); info!("HV alternating reversal {=u32}", hv.reversals); }
+                        Some(r) => stop_path(&mut hv, r, &freq_tx).await,
+                    }
+                }
+                hv.publish().await;
+                continue;
+            }
+            Either4::Third(reason) => { stop_path(&mut hv, reason, &freq_tx).await; hv.publish().await; continue; }
+            // The pulse train has finished and its output is already stopped
+            Either4::Fourth(_) => {
+                if hv.state == HvState::Running {
+                    info!("HV disable: pulse train complete");
+                    let _ = dac_tx.send(DacCmd::SetHvVolts(0.0)).await;
+                    let _ = hv.disable().await;
+                }
+                hv.publish().await;
+                continue;
+            }
+        };
         match cmd {
-            HvCommand::ForceStop => {
-                info!("HV ForceStop");
+            HvCommand::ResetFaults => match safety::reset_faults() {
+                Ok(()) => info!("HV faults reset"),
+                Err(_) => warn!("HV fault reset refused: not discharged"),
+            },
+            HvCommand::Arm if hv.step.is_some() => warn!("HV arm refused: step mode active"),
+            HvCommand::Enable if hv.step.is_some() => warn!("HV enable refused: step mode active"),
+            HvCommand::RequestPolarityToggle | HvCommand::SetPolarity(_) | HvCommand::SelectCin(_) if hv.step.is_some() => {
+                warn!("HV relay change refused: step mode active");
+            }
+            HvCommand::RequestPolarityToggle | HvCommand::SetPolarity(_) | HvCommand::SelectCin(_) if hv.alt.is_some() => {
+                warn!("HV relay change refused: alternating mode active");
+            }
+            HvCommand::StartAlternating(trigger) => match hv.start_alternating(trigger) {
+                Ok(()) => info!("HV alternating mode {}", trigger),
+                Err(e) => warn!("HV alternating refused: {}", e),
+            },
+            HvCommand::StopAlternating(final_pol) => {
+                hv.alt = None;
+                info!("HV alternating stopped after {=u32} reversals", hv.reversals);
+                if let Some(pol) = final_pol {
+                    if hv.pol != pol || !hv.polarity_relays_valid() {
+                        if let Some(r) = abortable(polarity_sequence(&mut hv, pol, &dac_tx, &freq_tx)).await { stop_path(&mut hv, r, &freq_tx).await; }
+                    }
+                }
+            }
+            HvCommand::StartStep(_) if hv.alt.is_some() => warn!("HV step refused: alternating mode active"),
+            HvCommand::StartStep(prog) => match hv.start_step(prog).await {
+                Ok(()) => info!("HV step mode {}", prog),
+                Err(e) => warn!("HV step refused: {}", e),
+            },
+            HvCommand::StopStep => { hv.stop_step().await; info!("HV step mode stopped"); }
+            HvCommand::Arm => match hv.arm(frequency_control::current_mhz()) {
+                Ok(()) => info!("HV armed"),
+                Err(e) => warn!("HV arm refused: {}", e),
+            },
+            HvCommand::Enable => {
                 let _ = dac_tx.send(DacCmd::SetHvVolts(0.0)).await;
-                let _ = freq_tx.send(FrequencyCmd::SetFrequency(0)).await;
-                let _ = hv.hv_on_update(0).await;
-                hv.state = HvState::Off;
+                match hv.enable(frequency_control::current_mhz()).await {
+                    Ok(()) => info!("HV enabled"),
+                    Err(e) => warn!("HV enable refused: {}", e),
+                }
             }
-            HvCommand::RequestPolarityToggle => {
-                info!("HV polarity toggle start");
+            HvCommand::Disable => {
+                info!("HV disable");
+                hv.alt = None;
                 let _ = dac_tx.send(DacCmd::SetHvVolts(0.0)).await;
-                let _ = freq_tx.send(FrequencyCmd::SetFrequency(0)).await;
-                let _ = hv.hv_on_update(0).await;
-                hv.state = HvState::Discharging;
-                hv.state = HvState::WaitingForDischarge;
-                Timer::after_millis(2150).await; // mandatory hold
-                hv.state = HvState::PreSetting;
-                // set new polarity
+                let _ = hv.disable().await;
+            }
+            HvCommand::RequestPolarityToggle => {
                 let new_pol = if hv.pol==Polarity::Positive { Polarity::Negative } else { Polarity::Positive };
-                let _ = hv.set_polarity(new_pol).await;
-                Timer::after_millis(1).await; hv.state = HvState::Completing;
-                hv.gpb |= GPB4 | GPB5; let _ = hv.apply().await; Timer::after_millis(1).await; hv.state = HvState::Toggling;
-                hv.gpb &= !(GPB4 | GPB5); let _ = hv.apply().await; Timer::after_millis(1).await; hv.state = HvState::Restoring;
-                Timer::after_millis(100).await; hv.state = HvState::Running;
-                info!("HV polarity switch complete");
+                if let Some(r) = abortable(polarity_sequence(&mut hv, new_pol, &dac_tx, &freq_tx)).await { stop_path(&mut hv, r, &freq_tx).await; }
+            }
+            HvCommand::SetPolarity(pol) => {
+                if hv.pol == pol && hv.polarity_relays_valid() {
+                    info!("HV polarity already {}", pol);
+                } else {
+                    if let Some(r) = abortable(polarity_sequence(&mut hv, pol, &dac_tx, &freq_tx)).await { stop_path(&mut hv, r, &freq_tx).await; }
+                }
+                match hv.confirm_polarity().await {
+                    Ok(p) => info!("HV polarity confirmed {}", p),
+                    Err(_) => error!("HV polarity latch mismatch"),
+                }
+            }
+            HvCommand::SelectCin(cin) => {
+                if hv.cin == cin { info!("HV Cin already {}", cin); }
+                else if let Some(r) = abortable(cin_sequence(&mut hv, cin, &dac_tx, &freq_tx)).await { stop_path(&mut hv, r, &freq_tx).await; }
             }
         }
+        hv.publish().await;
     }
 }
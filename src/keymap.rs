@@ -0,0 +1,121 @@
+use core::cell::{Cell, RefCell};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::Vec;
+use crate::gestures::{self, ButtonConfig, GestureConfig, Hold, DEFAULT_CONFIG};
+use crate::{Button, ButtonsEvent, CHORD_PC_FREQ, CHORD_PC_POL};
+
+/* Front panel key map: which action each button gesture triggers. Every board variant (the
+   PA10/PA15 straps) brings its own map and gesture timings; the map can then be edited over the
+   control interface. A gesture without a binding does nothing. */
+
+pub const MAX_BINDINGS: usize = 16;
+
+/// A gesture without its repeat step, as bound in the map.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum Gesture { Click(Button), DoubleClick(Button), Long(Button), Repeat(Button), Chord(u8) }
+
+impl Gesture {
+    /// The bound gesture and, for repeats, how many steps it is worth; `None` for events
+    /// that are not gestures.
+    pub fn of(e: ButtonsEvent) -> Option<(Self, u8)> {
+        match e {
+            ButtonsEvent::Click(b) => Some((Gesture::Click(b), 1)),
+            ButtonsEvent::DoubleClick(b) => Some((Gesture::DoubleClick(b), 1)),
+            ButtonsEvent::Long(b) => Some((Gesture::Long(b), 1)),
+            ButtonsEvent::Repeat { button, step } => Some((Gesture::Repeat(button), step)),
+            ButtonsEvent::Chord(mask) => Some((Gesture::Chord(mask), 1)),
+            ButtonsEvent::DeadManHeld => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum Action { StepUp, StepDown, StartRamp, Stop, EmergencyStop, TogglePolarity, CycleNext, CyclePrev, RecallPreset(u8), ToggleCapture, CycleCin, ResetFaults, ToggleLock }
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub struct Binding { pub gesture: Gesture, pub action: Action }
+
+const fn bind(gesture: Gesture, action: Action) -> Binding { Binding { gesture, action } }
+
+/// Panel layout of one board variant.
+pub struct Variant { pub name: &'static str, pub bindings: &'static [Binding], pub gestures: GestureConfig }
+
+/// Standard panel: PC steps / ramps, Polarity toggles and, held, stops the output,
+/// Freq cycles presets and toggles capture; PC+Polarity resets faults, PC+Freq locks the panel.
+const STANDARD: Variant = Variant {
+    name: "standard",
+    bindings: &[
+        bind(Gesture::Click(Button::Pc), Action::StepUp),
+        bind(Gesture::Long(Button::Pc), Action::StartRamp),
+        bind(Gesture::Click(Button::Polarity), Action::TogglePolarity),
+        bind(Gesture::Long(Button::Polarity), Action::Stop),
+        bind(Gesture::Click(Button::Freq), Action::CycleNext),
+        bind(Gesture::DoubleClick(Button::Freq), Action::CyclePrev),
+        bind(Gesture::Long(Button::Freq), Action::ToggleCapture),
+        bind(Gesture::Chord(CHORD_PC_POL), Action::ResetFaults),
+        bind(Gesture::Chord(CHORD_PC_FREQ), Action::ToggleLock),
+    ],
+    gestures: DEFAULT_CONFIG,
+};
+
+/// Bench panel: PC adjusts the setpoint both ways (held to run fast), Freq held recalls the
+/// first preset instead of entering capture.
+const BENCH: Variant = Variant {
+    name: "bench",
+    bindings: &[
+        bind(Gesture::Click(Button::Pc), Action::StepUp),
+        bind(Gesture::Repeat(Button::Pc), Action::StepUp),
+        bind(Gesture::DoubleClick(Button::Pc), Action::StepDown),
+        bind(Gesture::Click(Button::Polarity), Action::TogglePolarity),
+        bind(Gesture::Long(Button::Polarity), Action::Stop),
+        bind(Gesture::Click(Button::Freq), Action::CycleNext),
+        bind(Gesture::DoubleClick(Button::Freq), Action::CyclePrev),
+        bind(Gesture::Long(Button::Freq), Action::RecallPreset(0)),
+        bind(Gesture::Chord(CHORD_PC_POL), Action::ResetFaults),
+        bind(Gesture::Chord(CHORD_PC_FREQ), Action::ToggleLock),
+    ],
+    gestures: GestureConfig {
+        buttons: [
+            ButtonConfig { hold: Hold::Repeat, long_ms: 800, double_ms: 250 },
+            DEFAULT_CONFIG.buttons[1],
+            DEFAULT_CONFIG.buttons[2],
+        ],
+        ..DEFAULT_CONFIG
+    },
+};
+
+/// Indexed by board ID.
+const VARIANTS: [&Variant; 4] = [&STANDARD, &BENCH, &STANDARD, &STANDARD];
+
+pub fn variant(board_id: u8) -> &'static Variant { VARIANTS[board_id as usize & 3] }
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum KeymapError { Full }
+
+static MAP: Mutex<CriticalSectionRawMutex, RefCell<Vec<Binding, MAX_BINDINGS>>> = Mutex::new(RefCell::new(Vec::new()));
+static BOARD: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// Load the map and gesture timings of this board's variant.
+pub fn init(board_id: u8) {
+    BOARD.lock(|b| b.set(board_id));
+    let v = variant(board_id);
+    defmt::info!("Key map: board {=u8} -> {=str}", board_id, v.name);
+    MAP.lock(|m| *m.borrow_mut() = Vec::from_slice(v.bindings).unwrap());
+    gestures::set_config(v.gestures);
+}
+
+/// Back to the variant's own map and timings.
+pub fn defaults() { init(BOARD.lock(|b| b.get())); }
+
+pub fn lookup(g: Gesture) -> Option<Action> { MAP.lock(|m| m.borrow().iter().find(|b| b.gesture == g).map(|b| b.action)) }
+
+/// Bind `g` to `action`, replacing its previous binding; `None` unbinds it.
+pub fn set(g: Gesture, action: Option<Action>) -> Result<(), KeymapError> {
+    MAP.lock(|m| {
+        let mut m = m.borrow_mut();
+        m.retain(|b| b.gesture != g);
+        match action { Some(a) => m.push(bind(g, a)).map_err(|_| KeymapError::Full), None => Ok(()) }
+    })
+}
+
+pub fn bindings() -> Vec<Binding, MAX_BINDINGS> { MAP.lock(|m| m.borrow().clone()) }
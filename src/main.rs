@@ -13,39 +13,41 @@ use embassy_stm32::i2c::I2c;
 use embassy_stm32::gpio::{Input, Output, Level, Pull, Speed};
 use embassy_stm32::exti::ExtiInput;
 use embassy_stm32::dac::Dac;
-use embassy_stm32::usart::{Uart, Config as UartConfig};
+use embassy_stm32::flash::Flash;
 use embassy_stm32::time::Hertz;
 
 mod drivers;
 mod safety;
 mod hv_control;
+mod relays;
 mod dac_control;
 mod frequency_control;
 mod buttons;
+mod debounce;
+mod gestures;
 mod board_id;
+mod storage;
+mod control;
+mod envelope;
+mod keymap;
+mod panel;
+mod deadman;
 
 use drivers::mcp23017::Mcp23017;
 use drivers::mcp3424::Mcp3424;
+use drivers::soft_uart::SoftUart;
 use hv_control::HvCommand;
 use dac_control::DacCmd;
-use frequency_control::FrequencyCmd;
-
-#[derive(Clone, Copy, Debug, defmt::Format)]
-pub enum ButtonsEvent {
-    PcShort,        // PB0
-    PcLong,
-    PolarityShort,  // PA9
-    PolarityLong,
-    FreqShort,      // PA12
-    FreqLong,
-}
+use frequency_control::{FrequencyCmd, PresetCmd};
+use keymap::Action;
+
+pub use gestures::{Button, ButtonsEvent, CHORD_PC_FREQ, CHORD_PC_POL};
 
 bind_interrupts!(struct Irqs {
     I2C1_EV => embassy_stm32::i2c::InterruptHandler<peripherals::I2C1>;
     I2C1_ER => embassy_stm32::i2c::InterruptHandler<peripherals::I2C1>;
     I2C3_EV => embassy_stm32::i2c::InterruptHandler<peripherals::I2C3>;
     I2C3_ER => embassy_stm32::i2c::InterruptHandler<peripherals::I2C3>;
-    USART2 => embassy_stm32::usart::InterruptHandler<peripherals::USART2>;
 });
 
 static BUTTON_EVENTS: Channel<ButtonsEvent, 8> = Channel::new();
@@ -53,6 +55,12 @@ static FREQ_CH: Channel<FrequencyCmd, 8> = Channel::new();
 static DAC_CH: Channel<DacCmd, 8> = Channel::new();
 static HV_CH: Channel<HvCommand, 8> = Channel::new();
 
+/// Queue a button command without waiting: a task busy with a ramp or relay sequence must not
+/// hold up the buttons behind it, the stops above all.
+fn offer<T: defmt::Format + Copy>(ch: &'static Channel<T, 8>, cmd: T) {
+    if ch.try_send(cmd).is_err() { warn!("Button command {} dropped: busy", cmd); }
+}
+
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_stm32::init(Default::default());
@@ -60,7 +68,7 @@ async fn main(spawner: Spawner) {
     // I/O_Exp_RST (PA3) normally HIGH
     let mut io_exp_rst = Output::new(p.PA3, Level::High, Speed::Low);
     // KILL_N (PA6) active-low, start HIGH (not killed)
-    let mut kill_n = Output::new(p.PA6, Level::High, Speed::Low);
+    safety::init_kill(Output::new(p.PA6, Level::High, Speed::Low));
 
     // DAC1 (PA4)
     let mut dac = Dac::new(p.DAC);
@@ -79,11 +87,21 @@ async fn main(spawner: Spawner) {
     Timer::after_millis(1).await;
 
     // Drivers
+    // hv_task configures the expander and keeps HV disabled if that fails
     let expander = Mcp23017::new(i2c1, 0x20);
     let adc = Mcp3424::new(i2c3, 0x68);
 
     // Board ID PA10/PA15
-    let _id = board_id::read_board_id(p.PA10, p.PA15);
+    let id = board_id::read_board_id(p.PA10, p.PA15);
+    keymap::init(id);
+
+    envelope::init();
+
+    // Flash storage (last 8KB) for relay counters and presets
+    *storage::STORAGE.lock().await = Some(storage::Storage::new(Flash::new_blocking(p.FLASH)));
+
+    // Control interface: software UART, TX on PA8, RX on PA11 (both otherwise unused)
+    let uart = SoftUart::new(p.TIM7, p.PA8, p.PA11);
 
     // Buttons: PB0, PA9, PA12
     let pb0 = ExtiInput::new(Input::new(p.PB0, Pull::Up), p.EXTI0);
@@ -93,22 +111,47 @@ async fn main(spawner: Spawner) {
     // Spawn tasks
     spawner.spawn(buttons::buttons_task(pb0, pa9, pa12, BUTTON_EVENTS.sender())).unwrap();
 
-    spawner.spawn(frequency_control::frequency_task(p.PA5, p.TIM2, FREQ_CH.receiver(), HV_CH.sender())).unwrap();
+    spawner.spawn(frequency_control::frequency_task(p.PA5, p.PA2, p.PA1, p.TIM2, FREQ_CH.receiver())).unwrap();
     spawner.spawn(dac_control::dac_task(dac, DAC_CH.receiver())).unwrap();
-    spawner.spawn(safety::safety_task(adc, DAC_CH.sender(), HV_CH.sender())).unwrap();
+    spawner.spawn(safety::safety_task(adc)).unwrap();
     spawner.spawn(hv_control::hv_task(expander, DAC_CH.sender(), FREQ_CH.sender(), HV_CH.receiver())).unwrap();
+    spawner.spawn(control::control_task(uart, DAC_CH.sender(), HV_CH.sender(), FREQ_CH.sender())).unwrap();
 
     info!("Boot complete");
 
+    let mut guard = panel::Guard::new();
     loop {
         let evt = BUTTON_EVENTS.receive().await;
-        match evt {
-            ButtonsEvent::PcShort => { let _ = DAC_CH.sender().send(dac_control::DacCmd::ShortStep).await; }
-            ButtonsEvent::PcLong => { let _ = DAC_CH.sender().send(dac_control::DacCmd::StartRamp).await; }
-            ButtonsEvent::PolarityShort => { let _ = HV_CH.sender().send(hv_control::HvCommand::RequestPolarityToggle).await; }
-            ButtonsEvent::PolarityLong => {}
-            ButtonsEvent::FreqShort => { let _ = FREQ_CH.sender().send(frequency_control::FrequencyCmd::CycleNext).await; }
-            ButtonsEvent::FreqLong => { let _ = FREQ_CH.sender().send(frequency_control::FrequencyCmd::EnterInputCaptureMode).await; }
+        if evt == ButtonsEvent::DeadManHeld {
+            if panel::lock() != panel::Lock::Unlocked { warn!("Dead-man enable refused: panel locked"); continue; }
+            offer(&HV_CH, HvCommand::Arm);
+            offer(&HV_CH, HvCommand::Enable);
+            continue;
+        }
+        let Some((gesture, step)) = keymap::Gesture::of(evt) else { continue };
+        let Some(action) = keymap::lookup(gesture) else { debug!("Button {} unassigned", evt); continue };
+        let action = match guard.admit(gesture, action, panel::lock(), &panel::confirm_config(), embassy_time::Instant::now().as_millis()) {
+            Ok(a) => a,
+            Err(r) => { warn!("Button {} -> {} refused: {}", evt, action, r); continue; }
+        };
+        debug!("Button {} -> {}", evt, action);
+        match action {
+            Action::Stop => safety::stop(safety::StopReason::Operator),
+            Action::EmergencyStop => safety::stop(safety::StopReason::EmergencyStop),
+            Action::StepUp => offer(&DAC_CH, DacCmd::ShortStep(step as i8)),
+            Action::StepDown => offer(&DAC_CH, DacCmd::ShortStep(-(step as i8))),
+            Action::StartRamp => offer(&DAC_CH, DacCmd::StartRamp),
+            Action::TogglePolarity => offer(&HV_CH, HvCommand::RequestPolarityToggle),
+            Action::CycleCin => offer(&HV_CH, HvCommand::SelectCin(hv_control::status().cin.next())),
+            Action::ResetFaults => offer(&HV_CH, HvCommand::ResetFaults),
+            Action::CycleNext => offer(&FREQ_CH, FrequencyCmd::CycleNext),
+            Action::CyclePrev => offer(&FREQ_CH, FrequencyCmd::CyclePrev),
+            Action::RecallPreset(i) => offer(&FREQ_CH, FrequencyCmd::Preset(PresetCmd::Recall(i))),
+            Action::ToggleCapture => {
+                let cmd = if frequency_control::capture_active() { FrequencyCmd::ExitInputCaptureMode } else { FrequencyCmd::EnterInputCaptureMode };
+                offer(&FREQ_CH, cmd);
+            }
+            Action::ToggleLock => panel::toggle_lock(),
         }
     }
 }
@@ -0,0 +1,145 @@
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use crate::keymap::{Action, Gesture};
+
+/* Front panel policy between the key map and the actions it triggers:
+   - lock: while locked only the stops (and unlocking by the same gesture) get through. A lock set
+     over the control interface can only be lifted from there;
+   - confirmation: polarity toggles, ramp starts and Cin range changes can be made to require
+     a held gesture, or the same action twice within a window. */
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum Lock { Unlocked, Panel, Remote }
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum Confirm {
+    None,
+    /// Only accepted from a long press, repeat or chord, never from a click.
+    Hold,
+    /// A second trigger within `window_ms` executes; the first only arms.
+    Twice { window_ms: u32 },
+}
+
+/// Actions that can be made to need confirmation.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum Dangerous { Polarity, Ramp, Range }
+
+impl Dangerous {
+    pub fn of(a: Action) -> Option<Self> {
+        match a {
+            Action::TogglePolarity => Some(Dangerous::Polarity),
+            Action::StartRamp => Some(Dangerous::Ramp),
+            Action::CycleCin => Some(Dangerous::Range),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub struct ConfirmConfig { pub polarity: Confirm, pub ramp: Confirm, pub range: Confirm }
+
+impl ConfirmConfig {
+    pub fn get(&self, d: Dangerous) -> Confirm {
+        match d { Dangerous::Polarity => self.polarity, Dangerous::Ramp => self.ramp, Dangerous::Range => self.range }
+    }
+    pub fn set(&mut self, d: Dangerous, c: Confirm) {
+        match d { Dangerous::Polarity => self.polarity = c, Dangerous::Ramp => self.ramp = c, Dangerous::Range => self.range = c }
+    }
+}
+
+/// A polarity reversal from a single press is too easy to do by accident with HV on.
+pub const DEFAULT_CONFIRM: ConfirmConfig = ConfirmConfig {
+    polarity: Confirm::Twice { window_ms: 1500 },
+    ramp: Confirm::Hold,
+    range: Confirm::Twice { window_ms: 1500 },
+};
+
+static LOCK: Mutex<CriticalSectionRawMutex, Cell<Lock>> = Mutex::new(Cell::new(Lock::Unlocked));
+static CONFIRM: Mutex<CriticalSectionRawMutex, Cell<ConfirmConfig>> = Mutex::new(Cell::new(DEFAULT_CONFIRM));
+
+pub fn lock() -> Lock { LOCK.lock(|l| l.get()) }
+pub fn set_lock(l: Lock) { LOCK.lock(|c| c.set(l)); defmt::info!("Panel {}", l); }
+pub fn confirm_config() -> ConfirmConfig { CONFIRM.lock(|c| c.get()) }
+pub fn set_confirm(d: Dangerous, c: Confirm) { CONFIRM.lock(|cfg| { let mut v = cfg.get(); v.set(d, c); cfg.set(v) }); }
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum Refused { Locked, RemoteLock, HoldToConfirm, PressAgainToConfirm }
+
+/// Lock toggled from the panel; a remote lock stays until lifted remotely.
+pub fn toggle_lock() {
+    match lock() { Lock::Unlocked => set_lock(Lock::Panel), Lock::Panel => set_lock(Lock::Unlocked), Lock::Remote => {} }
+}
+
+/// Pending second-press confirmation. The lock and confirmation settings are passed in, not
+/// read from the statics, so a test can set up any combination.
+#[derive(Default)]
+pub struct Guard { armed: Option<(Action, u64)> }
+
+impl Guard {
+    pub const fn new() -> Self { Self { armed: None } }
+
+    /// Decide whether `action`, triggered by `g` at `now_ms`, may run now. `ToggleLock` is
+    /// admitted unless the lock is remote; applying it is up to the caller.
+    pub fn admit(&mut self, g: Gesture, action: Action, lock: Lock, cfg: &ConfirmConfig, now_ms: u64) -> Result<Action, Refused> {
+        if action == Action::ToggleLock { self.armed = None; return if lock == Lock::Remote { Err(Refused::RemoteLock) } else { Ok(action) }; }
+        if matches!(action, Action::Stop | Action::EmergencyStop) { self.armed = None; return Ok(action); }
+        if lock != Lock::Unlocked { self.armed = None; return Err(Refused::Locked); }
+        let Some(d) = Dangerous::of(action) else { self.armed = None; return Ok(action) };
+        match cfg.get(d) {
+            Confirm::None => Ok(action),
+            Confirm::Hold if matches!(g, Gesture::Long(_) | Gesture::Repeat(_) | Gesture::Chord(_)) => Ok(action),
+            Confirm::Hold => Err(Refused::HoldToConfirm),
+            Confirm::Twice { window_ms } => match self.armed.take() {
+                Some((a, at)) if a == action && now_ms - at <= window_ms as u64 => Ok(action),
+                _ => { self.armed = Some((action, now_ms)); Err(Refused::PressAgainToConfirm) }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Button;
+
+    const CLICK: Gesture = Gesture::Click(Button::Polarity);
+    const LONG: Gesture = Gesture::Long(Button::Pc);
+
+    #[test]
+    fn lock_admits_only_stop_and_unlock() {
+        let mut g = Guard::new();
+        for l in [Lock::Panel, Lock::Remote] {
+            assert_eq!(g.admit(CLICK, Action::CycleNext, l, &DEFAULT_CONFIRM, 0), Err(Refused::Locked));
+            assert_eq!(g.admit(LONG, Action::Stop, l, &DEFAULT_CONFIRM, 0), Ok(Action::Stop));
+        }
+        assert_eq!(g.admit(LONG, Action::ToggleLock, Lock::Panel, &DEFAULT_CONFIRM, 0), Ok(Action::ToggleLock));
+        assert_eq!(g.admit(LONG, Action::ToggleLock, Lock::Remote, &DEFAULT_CONFIRM, 0), Err(Refused::RemoteLock));
+    }
+
+    #[test]
+    fn second_press_within_the_window_confirms() {
+        let mut g = Guard::new();
+        let cfg = DEFAULT_CONFIRM;
+        assert_eq!(g.admit(CLICK, Action::TogglePolarity, Lock::Unlocked, &cfg, 0), Err(Refused::PressAgainToConfirm));
+        assert_eq!(g.admit(CLICK, Action::TogglePolarity, Lock::Unlocked, &cfg, 1500), Ok(Action::TogglePolarity));
+        // Confirmation is consumed, too late re-arms, another action in between disarms
+        assert_eq!(g.admit(CLICK, Action::TogglePolarity, Lock::Unlocked, &cfg, 1600), Err(Refused::PressAgainToConfirm));
+        assert_eq!(g.admit(CLICK, Action::TogglePolarity, Lock::Unlocked, &cfg, 3101), Err(Refused::PressAgainToConfirm));
+        assert_eq!(g.admit(CLICK, Action::CycleNext, Lock::Unlocked, &cfg, 3200), Ok(Action::CycleNext));
+        assert_eq!(g.admit(CLICK, Action::TogglePolarity, Lock::Unlocked, &cfg, 3300), Err(Refused::PressAgainToConfirm));
+    }
+
+    #[test]
+    fn hold_confirmation_refuses_clicks() {
+        let mut g = Guard::new();
+        assert_eq!(g.admit(Gesture::Click(Button::Pc), Action::StartRamp, Lock::Unlocked, &DEFAULT_CONFIRM, 0), Err(Refused::HoldToConfirm));
+        assert_eq!(g.admit(LONG, Action::StartRamp, Lock::Unlocked, &DEFAULT_CONFIRM, 0), Ok(Action::StartRamp));
+    }
+
+    #[test]
+    fn unconfirmed_actions_run_at_once() {
+        let mut cfg = DEFAULT_CONFIRM;
+        cfg.set(Dangerous::Polarity, Confirm::None);
+        assert_eq!(Guard::new().admit(CLICK, Action::TogglePolarity, Lock::Unlocked, &cfg, 0), Ok(Action::TogglePolarity));
+    }
+}
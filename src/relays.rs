@@ -0,0 +1,182 @@
+use bitfield::bitfield;
+
+bitfield! {
+    /// Output latch image of the MCP23017: GPB in bits 0-7, GPA in bits 8-15.
+    #[derive(Copy, Clone, PartialEq, Eq, Default)]
+    pub struct OutputImage(u16);
+    impl Debug;
+    pub pol_pos_a, set_pol_pos_a: 0;   // GPB0
+    pub pol_neg_a, set_pol_neg_a: 1;   // GPB1
+    pub pol_neg_b, set_pol_neg_b: 2;   // GPB2
+    pub pol_pos_b, set_pol_pos_b: 3;   // GPB3
+    pub step_pos, set_step_pos: 4;     // GPB4 +Step_vtg relay
+    pub ctgp, set_ctgp: 5;             // GPB5 CTGP_RELAY
+    pub hv_on, set_hv_on: 6;           // GPB6 HV_ON
+    pub cin1, set_cin1: 7;             // GPB7 Cin_RLY1
+    pub step_neg, set_step_neg: 14;    // GPA6 -Step_vtg relay
+    pub cin2, set_cin2: 15;            // GPA7 Cin_RLY2
+    pub u8, gpb, set_gpb: 7, 0;
+    pub u8, gpa, set_gpa: 15, 8;
+}
+
+impl defmt::Format for OutputImage {
+    fn format(&self, f: defmt::Formatter) { defmt::write!(f, "GPA={=u8:#04x} GPB={=u8:#04x}", self.gpa(), self.gpb()) }
+}
+
+/// Illegal relay combination or transition, caught before it reaches the expander.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum RelayError { BothPolarityPairs, PolarityPairIncomplete, BothStepRelays, PolarityWhileHvOn, StepWhileHvOn, CinWhileHvOn, Io }
+
+/// State of the four polarity relays as a whole.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum PolarityRelays { Open, Positive, Negative }
+
+impl OutputImage {
+    const POLARITY_MASK: u16 = 0x000F;
+    const CIN_MASK: u16 = 0x8080;
+
+    pub fn polarity(&self) -> Result<PolarityRelays, RelayError> {
+        let pos = (self.pol_pos_a(), self.pol_pos_b());
+        let neg = (self.pol_neg_a(), self.pol_neg_b());
+        match (pos, neg) {
+            ((false, false), (false, false)) => Ok(PolarityRelays::Open),
+            ((true, true), (false, false)) => Ok(PolarityRelays::Positive),
+            ((false, false), (true, true)) => Ok(PolarityRelays::Negative),
+            ((a, b), (c, d)) if (a || b) && (c || d) => Err(RelayError::BothPolarityPairs),
+            _ => Err(RelayError::PolarityPairIncomplete),
+        }
+    }
+
+    /// Check `next` on its own and as a transition from `self`.
+    pub fn validate(&self, next: &OutputImage) -> Result<(), RelayError> {
+        next.polarity()?;
+        if next.step_pos() && next.step_neg() { return Err(RelayError::BothStepRelays); }
+        let hv_on = self.hv_on() || next.hv_on();
+        if !hv_on { return Ok(()); }
+        if (self.0 ^ next.0) & Self::POLARITY_MASK != 0 { return Err(RelayError::PolarityWhileHvOn); }
+        if (self.0 ^ next.0) & Self::CIN_MASK != 0 { return Err(RelayError::CinWhileHvOn); }
+        if next.step_pos() || next.step_neg() { return Err(RelayError::StepWhileHvOn); }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum Relay { PolPosA, PolNegA, PolNegB, PolPosB, StepPos, Ctgp, HvOn, Cin1, StepNeg, Cin2 }
+
+pub const RELAY_COUNT: usize = 10;
+pub const RELAYS: [Relay; RELAY_COUNT] = [
+    Relay::PolPosA, Relay::PolNegA, Relay::PolNegB, Relay::PolPosB, Relay::StepPos,
+    Relay::Ctgp, Relay::HvOn, Relay::Cin1, Relay::StepNeg, Relay::Cin2,
+];
+
+/// Rated mechanical operations per relay; a warning is raised at `ENDURANCE_WARN_PCT` of it.
+pub const ENDURANCE_OPS: [u32; RELAY_COUNT] = [100_000, 100_000, 100_000, 100_000, 100_000, 100_000, 1_000_000, 100_000, 100_000, 100_000];
+pub const ENDURANCE_WARN_PCT: u32 = 90;
+
+impl Relay {
+    fn bit(self) -> u16 {
+        match self {
+            Relay::PolPosA => 0, Relay::PolNegA => 1, Relay::PolNegB => 2, Relay::PolPosB => 3, Relay::StepPos => 4,
+            Relay::Ctgp => 5, Relay::HvOn => 6, Relay::Cin1 => 7, Relay::StepNeg => 14, Relay::Cin2 => 15,
+        }
+    }
+    pub fn warn_threshold(self) -> u32 { ENDURANCE_OPS[self as usize] / 100 * ENDURANCE_WARN_PCT }
+}
+
+/// Per-relay actuation counts; one operation is counted per energise.
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq, Default)]
+pub struct RelayCounters { pub ops: [u32; RELAY_COUNT] }
+
+impl RelayCounters {
+    pub const BYTES: usize = RELAY_COUNT * 4;
+
+    /// Count relays energised by `prev -> next`; calls `on_warn` for each that just reached its warning threshold.
+    pub fn record(&mut self, prev: &OutputImage, next: &OutputImage, mut on_warn: impl FnMut(Relay, u32)) -> bool {
+        let rising = !prev.0 & next.0;
+        let mut any = false;
+        for r in RELAYS {
+            if rising & (1 << r.bit()) == 0 { continue; }
+            let n = &mut self.ops[r as usize];
+            *n = n.saturating_add(1);
+            any = true;
+            if *n == r.warn_threshold() { on_warn(r, *n); }
+        }
+        any
+    }
+    pub fn near_end_of_life(&self) -> impl Iterator<Item = (Relay, u32)> + '_ {
+        RELAYS.into_iter().map(|r| (r, self.ops[r as usize])).filter(|(r, n)| *n >= r.warn_threshold())
+    }
+    pub fn to_bytes(&self) -> [u8; Self::BYTES] {
+        let mut out = [0u8; Self::BYTES];
+        for (i, n) in self.ops.iter().enumerate() { out[i * 4..i * 4 + 4].copy_from_slice(&n.to_le_bytes()); }
+        out
+    }
+    pub fn from_bytes(b: &[u8; Self::BYTES]) -> Self {
+        let mut c = Self::default();
+        for (i, n) in c.ops.iter_mut().enumerate() { *n = u32::from_le_bytes([b[i * 4], b[i * 4 + 1], b[i * 4 + 2], b[i * 4 + 3]]); }
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positive() -> OutputImage { let mut o = OutputImage::default(); o.set_pol_pos_a(true); o.set_pol_pos_b(true); o }
+    fn negative() -> OutputImage { let mut o = OutputImage::default(); o.set_pol_neg_a(true); o.set_pol_neg_b(true); o }
+
+    #[test]
+    fn both_polarity_pairs_rejected() {
+        let mut next = positive();
+        next.set_pol_neg_a(true); next.set_pol_neg_b(true);
+        assert_eq!(OutputImage::default().validate(&next), Err(RelayError::BothPolarityPairs));
+        // One relay of the other pair is enough
+        let mut next = positive();
+        next.set_pol_neg_b(true);
+        assert_eq!(OutputImage::default().validate(&next), Err(RelayError::BothPolarityPairs));
+    }
+
+    #[test]
+    fn incomplete_polarity_pair_rejected() {
+        for set in [OutputImage::set_pol_pos_a, OutputImage::set_pol_pos_b, OutputImage::set_pol_neg_a, OutputImage::set_pol_neg_b] {
+            let mut next = OutputImage::default();
+            set(&mut next, true);
+            assert_eq!(OutputImage::default().validate(&next), Err(RelayError::PolarityPairIncomplete));
+        }
+        assert_eq!(OutputImage::default().validate(&positive()), Ok(()));
+        assert_eq!(OutputImage::default().validate(&negative()), Ok(()));
+    }
+
+    #[test]
+    fn both_step_relays_rejected() {
+        let mut next = positive();
+        next.set_step_pos(true); next.set_step_neg(true);
+        assert_eq!(positive().validate(&next), Err(RelayError::BothStepRelays));
+        next.set_step_neg(false);
+        assert_eq!(positive().validate(&next), Ok(()));
+    }
+
+    #[test]
+    fn changes_while_hv_on_rejected() {
+        let mut on = positive();
+        on.set_hv_on(true);
+        let mut next = negative();
+        next.set_hv_on(true);
+        assert_eq!(on.validate(&next), Err(RelayError::PolarityWhileHvOn));
+        let mut next = on;
+        next.set_cin1(true);
+        assert_eq!(on.validate(&next), Err(RelayError::CinWhileHvOn));
+        let mut next = on;
+        next.set_step_pos(true);
+        assert_eq!(on.validate(&next), Err(RelayError::StepWhileHvOn));
+        // The image that asserts HV_ON is checked as well
+        let mut next = on;
+        next.set_cin2(true);
+        assert_eq!(positive().validate(&next), Err(RelayError::CinWhileHvOn));
+        // Releasing HV_ON alone, and other relays such as CTGP, stay allowed
+        assert_eq!(on.validate(&positive()), Ok(()));
+        let mut next = on;
+        next.set_ctgp(true);
+        assert_eq!(on.validate(&next), Ok(()));
+    }
+}
@@ -1,20 +1,74 @@
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use defmt::*;
 use embassy_time::Timer;
-use embassy_sync::channel::mpmc::Channel;
+use embassy_stm32::gpio::Output;
+use embassy_stm32::peripherals::PA6;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
 use crate::drivers::mcp3424::Mcp3424;
-use crate::dac_control::DacCmd;
-use crate::hv_control::HvCommand;
+use crate::dac_control;
+use crate::drivers::pulse_timer::PulseTimer;
 
 const DISCHARGE_THRESH_V: f32 = 0.045_409; // |ADC| < 0.045409V
 const OV_WARN_V: f32 = 1.527; // >310V equivalent
 const EMERG_SHUT_V: f32 = 1.724; // >350V equivalent
 
+/// Set on emergency shutdown; blocks HV enable until cleared.
+pub static FAULT_LATCHED: AtomicBool = AtomicBool::new(false);
+/// Both ADC channels read below the discharge threshold on the last sample.
+pub static DISCHARGED: AtomicBool = AtomicBool::new(false);
+
+/// Bumped by every `stop()`; long-running sequences compare it to notice a stop of any kind.
+static STOP_GEN: AtomicU32 = AtomicU32::new(0);
+
+pub fn stop_generation() -> u32 { STOP_GEN.load(Ordering::Acquire) }
+pub fn fault_latched() -> bool { FAULT_LATCHED.load(Ordering::Acquire) }
+pub fn discharged() -> bool { DISCHARGED.load(Ordering::Acquire) }
+
+/* Priority stop path. `stop()` never waits on a channel: it forces PA5 low, zeroes the DAC register
+   and the setpoint the next step or ramp starts from, aborts a running ramp and,
+   for emergencies, pulls KILL_N low before returning, then wakes `hv_task` through STOP_SIGNAL.
+   `hv_task` drops HV_ON within one expander write (~0.5 ms at 100 kHz) of being polled,
+   including while it sits in a polarity or Cin hold, which the signal aborts. */
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum StopReason { Overvoltage, Operator, EmergencyStop, OutputStopped, OutputFault, DeadMan, OutsideEnvelope }
+
+impl StopReason {
+    /// Emergencies assert KILL_N and latch a fault that must be reset explicitly. A plain
+    /// operator stop does not; `EmergencyStop` is the operator's latching one.
+    pub fn is_emergency(self) -> bool { matches!(self, StopReason::Overvoltage | StopReason::EmergencyStop | StopReason::OutputFault) }
+}
+
+pub static STOP_SIGNAL: Signal<CriticalSectionRawMutex, StopReason> = Signal::new();
+static KILL_N: Mutex<CriticalSectionRawMutex, RefCell<Option<Output<'static, PA6>>>> = Mutex::new(RefCell::new(None));
+
+pub fn init_kill(kill_n: Output<'static, PA6>) { KILL_N.lock(|k| k.replace(Some(kill_n))); }
+
+/// Callable from any task; takes effect on the DAC and KILL_N before it returns.
+pub fn stop(reason: StopReason) {
+    PulseTimer::halt();
+    embassy_stm32::pac::DAC1.dhr12r(0).write(|w| w.set_dhr(0));
+    dac_control::zero_setpoint();
+    STOP_GEN.fetch_add(1, Ordering::AcqRel);
+    if reason.is_emergency() {
+        FAULT_LATCHED.store(true, Ordering::Release);
+        KILL_N.lock(|k| if let Some(pin) = k.borrow_mut().as_mut() { pin.set_low(); });
+    }
+    STOP_SIGNAL.signal(reason);
+}
+
+/// Release KILL_N and clear the latched fault once the stage reads discharged.
+pub fn reset_faults() -> Result<(), ()> {
+    if !discharged() { return Err(()); }
+    FAULT_LATCHED.store(false, Ordering::Release);
+    KILL_N.lock(|k| if let Some(pin) = k.borrow_mut().as_mut() { pin.set_high(); });
+    Ok(())
+}
+
 #[embassy_executor::task]
-pub async fn safety_task<'d>(
-    mut adc: Mcp3424<'d>,
-    dac_tx: Channel<DacCmd, 8>::Sender,
-    hv_tx: Channel<HvCommand, 8>::Sender,
-) {
+pub async fn safety_task<'d>(mut adc: Mcp3424<'d>) {
     loop {
         Timer::after_millis(100).await;
         let ch1 = adc.read_channel_uv(1).await;
@@ -25,13 +79,14 @@ pub async fn safety_task<'d>(
             info!("ADC CH1={=f32}V CH2={=f32}V", v1, v2);
             let a1 = v1.abs();
             let a2 = v2.abs();
+            DISCHARGED.store(a1 < DISCHARGE_THRESH_V && a2 < DISCHARGE_THRESH_V, Ordering::Release);
             if a1 > OV_WARN_V || a2 > OV_WARN_V { warn!("OV warn >310V"); }
             if a1 > EMERG_SHUT_V || a2 > EMERG_SHUT_V {
                 error!("Emergency shutdown >350V");
-                let _ = dac_tx.send(DacCmd::SetHvVolts(0.0)).await;
-                let _ = hv_tx.send(HvCommand::ForceStop).await;
+                stop(StopReason::Overvoltage);
             }
         } else {
+            DISCHARGED.store(false, Ordering::Release);
             warn!("ADC read error");
         }
     }
@@ -0,0 +1,127 @@
+use defmt::*;
+use embassy_stm32::flash::{Blocking, Flash};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+
+/* Last 8 KiB of flash (four 2 KiB pages) are kept out of FLASH in memory.x.
+   Each region owns two pages used as an append-only log of fixed-size records:
+   [magic u16][len u16][crc32 u32][seq u32][pad u32][payload padded to 8], the CRC covering
+   seq and payload. The record with the highest seq wins. When the page holding it is full the
+   other page is erased and the new record written there, so the previous record survives until
+   the new one is complete. */
+
+const STORAGE_OFFSET: u32 = 0x3_E000;
+const PAGE_SIZE: u32 = 2048;
+const HEADER_LEN: usize = 16;
+const MAGIC: u16 = 0x5053;
+pub const MAX_PAYLOAD: usize = 120;
+
+#[derive(Copy, Clone, Debug, defmt::Format, PartialEq)]
+pub enum Region { RelayCounters, Presets }
+
+impl Region {
+    fn page(self, i: u32) -> u32 {
+        match self { Region::RelayCounters => STORAGE_OFFSET + i * PAGE_SIZE, Region::Presets => STORAGE_OFFSET + (2 + i) * PAGE_SIZE }
+    }
+}
+
+/// Where the newest record of a region lives.
+#[derive(Copy, Clone)]
+struct Latest { page: u32, off: u32, seq: u32 }
+
+pub struct Storage<'d> { flash: Flash<'d, Blocking> }
+
+pub static STORAGE: Mutex<CriticalSectionRawMutex, Option<Storage<'static>>> = Mutex::new(None);
+
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for b in parts.iter().flat_map(|p| p.iter()) {
+        crc ^= *b as u32;
+        for _ in 0..8 { crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 }; }
+    }
+    !crc
+}
+
+fn slot_len(len: usize) -> u32 { (HEADER_LEN + ((len + 7) & !7)) as u32 }
+
+impl<'d> Storage<'d> {
+    pub fn new(flash: Flash<'d, Blocking>) -> Self { Self { flash } }
+
+    /// Scan both pages of the region for the newest valid record of `buf.len()` bytes, copying it into `buf`.
+    fn find_latest(&mut self, region: Region, buf: &mut [u8]) -> Option<Latest> {
+        let slot = slot_len(buf.len());
+        let mut found: Option<Latest> = None;
+        for page in [region.page(0), region.page(1)] {
+            let mut off = 0;
+            while off + slot <= PAGE_SIZE {
+                let mut hdr = [0u8; HEADER_LEN];
+                if self.flash.blocking_read(page + off, &mut hdr).is_err() { break; }
+                let magic = u16::from_le_bytes([hdr[0], hdr[1]]);
+                if magic == 0xFFFF { break; }
+                let len = u16::from_le_bytes([hdr[2], hdr[3]]) as usize;
+                let crc = u32::from_le_bytes([hdr[4], hdr[5], hdr[6], hdr[7]]);
+                let seq = u32::from_le_bytes([hdr[8], hdr[9], hdr[10], hdr[11]]);
+                let mut data = [0u8; MAX_PAYLOAD];
+                if magic == MAGIC && len == buf.len()
+                    && found.map_or(true, |f| seq > f.seq)
+                    && self.flash.blocking_read(page + off + HEADER_LEN as u32, &mut data[..len]).is_ok()
+                    && crc32(&[&hdr[8..12], &data[..len]]) == crc
+                {
+                    buf.copy_from_slice(&data[..len]);
+                    found = Some(Latest { page, off, seq });
+                }
+                off += slot;
+            }
+        }
+        found
+    }
+
+    pub fn load(&mut self, region: Region, buf: &mut [u8]) -> Result<(), ()> {
+        if buf.len() > MAX_PAYLOAD { return Err(()); }
+        self.find_latest(region, buf).map(|_| ()).ok_or(())
+    }
+
+    pub fn store(&mut self, region: Region, data: &[u8]) -> Result<(), ()> {
+        if data.len() > MAX_PAYLOAD { return Err(()); }
+        let slot = slot_len(data.len());
+        let mut scratch = [0u8; MAX_PAYLOAD];
+        let latest = self.find_latest(region, &mut scratch[..data.len()]);
+        let seq = latest.map_or(0, |l| l.seq.wrapping_add(1));
+        // First erased slot after the newest record in its page; when that page is full (or nothing
+        // was stored yet) start over on the other page, leaving the newest record in place
+        let mut target = None;
+        if let Some(l) = latest {
+            let mut off = l.off + slot;
+            while off + slot <= PAGE_SIZE {
+                let mut hdr = [0u8; 2];
+                self.flash.blocking_read(l.page + off, &mut hdr).map_err(|_| ())?;
+                if hdr == [0xFF, 0xFF] { target = Some(l.page + off); break; }
+                off += slot;
+            }
+        }
+        let addr = match target {
+            Some(a) => a,
+            None => {
+                let page = match latest { Some(l) if l.page == region.page(0) => region.page(1), _ => region.page(0) };
+                info!("Storage {} switching page", region);
+                self.flash.blocking_erase(page, page + PAGE_SIZE).map_err(|_| ())?;
+                page
+            }
+        };
+        let mut rec = [0xFFu8; HEADER_LEN + MAX_PAYLOAD + 8];
+        rec[0..2].copy_from_slice(&MAGIC.to_le_bytes());
+        rec[2..4].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        rec[8..12].copy_from_slice(&seq.to_le_bytes());
+        rec[4..8].copy_from_slice(&crc32(&[&seq.to_le_bytes(), data]).to_le_bytes());
+        rec[HEADER_LEN..HEADER_LEN + data.len()].copy_from_slice(data);
+        self.flash.blocking_write(addr, &rec[..slot as usize]).map_err(|_| ())
+    }
+}
+
+pub async fn load(region: Region, buf: &mut [u8]) -> Result<(), ()> {
+    match STORAGE.lock().await.as_mut() { Some(s) => s.load(region, buf), None => Err(()) }
+}
+
+pub async fn store(region: Region, data: &[u8]) -> Result<(), ()> {
+    match STORAGE.lock().await.as_mut() { Some(s) => s.store(region, data), None => Err(()) }
+}